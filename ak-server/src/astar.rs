@@ -0,0 +1,106 @@
+//! A* pathfinding over a [ServerMap], the same rules as the client's so paths look the same on both
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use rustc_hash::FxHashSet;
+
+use crate::hashmap;
+use crate::types_game::{ServerMap, Tile, TilePos, TILE_SIZE};
+
+/// Gets the manhattan distance between two points
+fn manhattan_distance(from: TilePos, to: TilePos) -> u32 {
+    from.0.abs_diff(to.0) + from.1.abs_diff(to.1)
+}
+
+/// Checks if a point can be walked on
+pub fn walkable(point: TilePos, map: &ServerMap, blocked: &FxHashSet<TilePos>) -> bool {
+    map.get(point) == Tile::Air && !blocked.contains(&point)
+}
+
+/// Returns a list of valid moves from a point. Diagonals are only allowed if both sides are free, so corners aren't cut
+fn neighbors(point: TilePos, map: &ServerMap, blocked: &FxHashSet<TilePos>) -> Vec<TilePos> {
+    let mut children = vec![];
+    let offset = |x_diff: i64, y_diff: i64| -> Option<TilePos> {
+        let x = u32::try_from(point.0 as i64 + x_diff).ok()?;
+        let y = u32::try_from(point.1 as i64 + y_diff).ok()?;
+        Some((x, y))
+    };
+
+    for (x_diff, y_diff) in [(0, 1), (0, -1), (1, 0), (-1, 0)] {
+        if let Some(new_point) = offset(x_diff, y_diff) {
+            if walkable(new_point, map, blocked) {
+                children.push(new_point);
+            }
+        }
+    }
+
+    for (x_diff, y_diff) in [(1, 1), (1, -1), (-1, 1), (-1, -1)] {
+        let (Some(new_point), Some(side_x), Some(side_y)) =
+            (offset(x_diff, y_diff), offset(x_diff, 0), offset(0, y_diff))
+        else {
+            continue;
+        };
+        if map.get(side_x) == Tile::Air
+            && map.get(side_y) == Tile::Air
+            && walkable(new_point, map, blocked)
+        {
+            children.push(new_point);
+        }
+    }
+
+    children
+}
+
+/// Returns a path of world positions from start to goal using the A* algorithm, or `None` if no path is found
+pub fn astar(
+    start: TilePos,
+    goal: TilePos,
+    map: &ServerMap,
+    blocked: &FxHashSet<TilePos>,
+) -> Option<Vec<(f32, f32)>> {
+    let mut parents = hashmap! {};
+    let mut costs = hashmap! {};
+    let mut queue = BinaryHeap::new();
+
+    queue.push(Reverse((0, start)));
+    parents.insert(start, start);
+    costs.insert(start, 0);
+
+    while let Some(Reverse((_, current))) = queue.pop() {
+        if current == goal {
+            break;
+        }
+
+        for neighbor in neighbors(current, map, blocked) {
+            let new_cost = costs[&current] + 1;
+            if costs.get(&neighbor).is_none_or(|cost| new_cost < *cost) {
+                costs.insert(neighbor, new_cost);
+                queue.push(Reverse((
+                    new_cost + manhattan_distance(neighbor, goal),
+                    neighbor,
+                )));
+                parents.insert(neighbor, current);
+            }
+        }
+    }
+
+    if !costs.contains_key(&goal) {
+        return None;
+    }
+
+    let mut path = vec![];
+    let mut current = goal;
+    while current != start {
+        path.push(tile_to_world(current));
+        current = parents[&current];
+    }
+    path.push(tile_to_world(start));
+    path.reverse();
+    Some(path)
+}
+
+/// Converts a tile to the world position of its top-left
+pub fn tile_to_world(pos: TilePos) -> (f32, f32) {
+    (pos.0 as f32 * TILE_SIZE, pos.1 as f32 * TILE_SIZE)
+}
@@ -0,0 +1,200 @@
+//! Addresses that aren't allowed to connect. Anything they send is dropped before it is parsed
+//!
+//! Addresses collect strikes for malformed packets and ratelimited requests. Enough strikes get a temporary ban, and enough temporary bans a permanent one. Bans are saved to [crate::config::ServerConfig::bans_file] so they survive restarts
+
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use ak_server::hashmap;
+use ak_server::util::now;
+use lazy_static::lazy_static;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, warn};
+
+use crate::config::config;
+use crate::session::kick_address;
+
+/// Strikes are forgotten after this long without a new one, in milliseconds
+const STRIKE_DECAY: u64 = 60_000;
+
+/// How many temporary bans an address has had is forgotten after this long without a strike, in milliseconds
+const STRIKE_MEMORY: u64 = 24 * 60 * 60 * 1000;
+
+/// How often expired bans and strikes are cleared
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Ban {
+    /// Shown to the connections kicked by the ban
+    pub reason: String,
+    /// When the ban ends, in milliseconds. `None` if it is permanent
+    pub until: Option<u64>,
+}
+impl Ban {
+    fn active(&self, now: u64) -> bool {
+        self.until.is_none_or(|until| until > now)
+    }
+}
+
+/// Why an address got a strike
+#[derive(Debug, Clone, Copy)]
+pub enum Strike {
+    /// A packet or request that couldn't be parsed, clients never send these by accident
+    Malformed,
+    /// A request that hit a ratelimit, which clients can do by accident now and then
+    Ratelimited,
+}
+impl Strike {
+    fn weight(self) -> u32 {
+        match self {
+            Strike::Malformed => 3,
+            Strike::Ratelimited => 1,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Strikes {
+    count: u32,
+    /// Time of the last strike, in milliseconds
+    last: u64,
+    /// Temporary bans the address has had
+    temp_bans: u32,
+}
+
+lazy_static! {
+    /// Map of every banned address to its ban
+    static ref BANNED: Mutex<FxHashMap<IpAddr, Ban>> = Mutex::from(hashmap! {});
+    /// Map of every address with recent strikes to them
+    static ref STRIKES: Mutex<FxHashMap<IpAddr, Strikes>> = Mutex::from(hashmap! {});
+}
+
+/// Writes every ban to [crate::config::ServerConfig::bans_file], failing only logs as the bans still hold until restart
+fn save(bans: &FxHashMap<IpAddr, Ban>) {
+    let path = &config().bans_file;
+    let result = to_string_pretty(bans, PrettyConfig::new())
+        .map_err(|err| err.to_string())
+        .and_then(|str| std::fs::write(path, str + "\n").map_err(|err| err.to_string()));
+    if let Err(err) = result {
+        error!(path, err, "Failed to save bans");
+    }
+}
+
+/// Loads the saved bans, dropping expired ones. A missing file means no bans, returns how many were loaded
+pub fn load_bans() -> Result<usize, String> {
+    let path = &config().bans_file;
+    if !Path::new(path).exists() {
+        return Ok(0);
+    }
+
+    let str =
+        std::fs::read_to_string(path).map_err(|err| format!("Failed to read \"{path}\"; {err}"))?;
+    let mut bans: FxHashMap<IpAddr, Ban> =
+        ron::from_str(&str).map_err(|err| format!("Invalid bans \"{path}\"; {err}"))?;
+    bans.retain(|_, ban| ban.active(now()));
+
+    let count = bans.len();
+    *BANNED.lock().unwrap() = bans;
+    Ok(count)
+}
+
+/// Bans an address and kicks every connection from it, `duration` is in seconds and `None` bans permanently. Returns how many connections were kicked
+pub fn ban(ip: IpAddr, reason: &str, duration: Option<u64>) -> usize {
+    let ban = Ban {
+        reason: reason.to_string(),
+        until: duration.map(|duration| now() + duration * 1000),
+    };
+    warn!(%ip, reason, until = ?ban.until, "Banned");
+
+    let mut bans = BANNED.lock().unwrap();
+    bans.insert(ip, ban);
+    save(&bans);
+    drop(bans);
+
+    kick_address(ip, reason)
+}
+
+/// Lifts a ban and forgets the address's strikes, returns `false` if the address wasn't banned
+pub fn unban(ip: IpAddr) -> bool {
+    STRIKES.lock().unwrap().remove(&ip);
+
+    let mut bans = BANNED.lock().unwrap();
+    if bans.remove(&ip).is_none() {
+        return false;
+    }
+    save(&bans);
+    true
+}
+
+pub fn is_banned(ip: IpAddr) -> bool {
+    BANNED
+        .lock()
+        .unwrap()
+        .get(&ip)
+        .is_some_and(|ban| ban.active(now()))
+}
+
+/// Returns every banned address with its ban
+pub fn banned() -> Vec<(IpAddr, Ban)> {
+    let now = now();
+    BANNED
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, ban)| ban.active(now))
+        .map(|(ip, ban)| (*ip, ban.clone()))
+        .collect()
+}
+
+/// Counts a strike against an address. Reaching [crate::config::ServerConfig::max_strikes] bans it for [crate::config::ServerConfig::temp_ban], or permanently once it has had [crate::config::ServerConfig::max_temp_bans]. Returns whether it was banned
+pub fn strike(ip: IpAddr, kind: Strike) -> bool {
+    let now = now();
+    let mut strikes = STRIKES.lock().unwrap();
+    let record = strikes.entry(ip).or_default();
+    if now.saturating_sub(record.last) > STRIKE_DECAY {
+        record.count = 0;
+    }
+    record.count += kind.weight();
+    record.last = now;
+    debug!(%ip, ?kind, strikes = record.count, "Strike");
+
+    if record.count < config().max_strikes {
+        return false;
+    }
+    record.count = 0;
+    let permanent = record.temp_bans >= config().max_temp_bans;
+    record.temp_bans += 1;
+    drop(strikes);
+
+    if permanent {
+        ban(ip, "Banned for abuse", None);
+    } else {
+        ban(ip, "Temporarily banned for abuse", Some(config().temp_ban));
+    }
+    true
+}
+
+/// Clears expired bans and strikes that are no longer remembered, runs forever
+pub async fn expire_bans() {
+    let mut interval = tokio::time::interval(EXPIRE_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let now = now();
+        STRIKES
+            .lock()
+            .unwrap()
+            .retain(|_, record| now.saturating_sub(record.last) <= STRIKE_MEMORY);
+
+        let mut bans = BANNED.lock().unwrap();
+        let len = bans.len();
+        bans.retain(|_, ban| ban.active(now));
+        if bans.len() != len {
+            save(&bans);
+        }
+    }
+}
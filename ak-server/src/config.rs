@@ -0,0 +1,409 @@
+//! Server settings, loaded from a RON file and overridden from the command line, validated once at startup
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use ak_server::fragment::{DEFAULT_MAX_DATAGRAM, MAX_UDP_PAYLOAD};
+use ak_server::types_client::ClientRequest;
+use derive_new::new;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::EnvFilter;
+
+use crate::ratelimit::RateLimit;
+
+#[cfg(debug_assertions)]
+const CONFIG_PATH: &str = "./ak-server/config.ron";
+#[cfg(not(debug_assertions))]
+const CONFIG_PATH: &str = "./config.ron";
+
+/// Relative to the config file, see [ServerConfig::load]
+const BANS_PATH: &str = "bans.ron";
+
+/// Relative to the config file, see [ServerConfig::load]
+const PROFILES_PATH: &str = "profiles.redb";
+
+/// Smallest datagram allowed, anything smaller can't fit a fragment header and some data
+const MIN_DATAGRAM: usize = 128;
+
+/// Highest tick rate allowed, in ticks per second
+const MAX_TICK_RATE: u32 = 128;
+
+const USAGE: &str = "Usage: ak-server [options]
+
+Options:
+    --config <path>           Config file to load, created with the defaults if missing
+    --address <ip>            Address to bind to
+    --port <port>             UDP port
+    --ws-port <port>          WebSocket port
+    --max-connections <n>     Most connections with a session at once
+    --max-link-sessions <n>   Most sessions through one link at once
+    --max-address-sessions <n>
+                              Most sessions from one address at once
+    --max-links <n>           Most UDP links at once
+    --max-address-links <n>   Most UDP links from one address at once
+    --max-games <n>           Most games running at once
+    --tick-rate <n>           Game ticks per second
+    --max-datagram <bytes>    Biggest datagram sent
+    --backfill-ai <bool>      Fill matches with AI players after --backfill-wait
+    --backfill-wait <secs>    How long a queued player waits before AI players are added
+    --reconnect-grace <secs>  How long a dropped player's slot is kept for them to reconnect
+    --log-level <filter>      What gets logged, like info or ak_server=debug. RUST_LOG takes priority
+    --metrics <bool>          Serve metrics in the Prometheus format on localhost
+    --metrics-port <port>     Port the metrics are served on
+    --max-strikes <n>         Strikes before an address is temporarily banned
+    --temp-ban <secs>         How long a temporary ban lasts
+    --max-temp-bans <n>       Temporary bans before the next one is permanent
+    --bans-file <path>        Where bans are saved
+    --profiles-file <path>    Database profiles are kept in
+    --help                    Print this message
+
+Ratelimits are only set in the config file";
+
+/// Ratelimits of the request kinds that don't use [ServerConfig::default_ratelimit], lobby requests are slow and gameplay commands fast. `Connect` is limited per link rather than per session
+fn default_ratelimits() -> BTreeMap<String, RateLimit> {
+    [
+        ("Connect", RateLimit::new(3, 0.2)),
+        ("Rename", RateLimit::new(2, 0.2)),
+        ("CreateProfile", RateLimit::new(2, 0.1)),
+        ("Login", RateLimit::new(3, 0.5)),
+        ("CreateGame", RateLimit::new(2, 0.2)),
+        ("JoinGame", RateLimit::new(3, 1.0)),
+        ("JoinByCode", RateLimit::new(3, 0.5)),
+        ("Spectate", RateLimit::new(3, 1.0)),
+        ("Reconnect", RateLimit::new(3, 1.0)),
+        ("ListGames", RateLimit::new(3, 1.0)),
+        ("ChatMessage", RateLimit::new(3, 1.0)),
+        ("MoveWorker", RateLimit::new(30, 20.0)),
+        ("AssignOre", RateLimit::new(30, 20.0)),
+        ("PlaceBuilding", RateLimit::new(10, 5.0)),
+        ("Cancel", RateLimit::new(30, 20.0)),
+    ]
+    .into_iter()
+    .map(|(kind, limit)| (kind.to_string(), limit))
+    .collect()
+}
+
+/// Settings missing from the file keep their defaults, so config files from older versions still load
+#[derive(Debug, Serialize, Deserialize, Clone, new)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Address both listeners bind to
+    #[new(value = "String::from(\"127.0.0.1\")")]
+    pub address: String,
+
+    /// Port of the UDP socket
+    #[new(value = "8080")]
+    pub port: u16,
+
+    /// Port of the WebSocket listener
+    #[new(value = "8090")]
+    pub ws_port: u16,
+
+    /// Most connections that can have a session at once, further connects get [ak_server::types_server::ErrorCode::ServerFull]
+    #[new(value = "256")]
+    pub max_connections: usize,
+
+    /// Most sessions that can be routed through one link at once, further connects get [ak_server::types_server::ErrorCode::TooManySessions]
+    #[new(value = "2")]
+    pub max_link_sessions: usize,
+
+    /// Most sessions that can come from one address at once, further connects get [ak_server::types_server::ErrorCode::TooManySessions]
+    #[new(value = "8")]
+    pub max_address_sessions: usize,
+
+    /// Most UDP links at once, datagrams from further addresses are dropped
+    #[new(value = "4096")]
+    pub max_links: usize,
+
+    /// Most UDP links from one IP at once, one for every port it sends from
+    #[new(value = "16")]
+    pub max_address_links: usize,
+
+    /// Most games that can exist at once, further creates get [ak_server::types_server::ErrorCode::TooManyGames]
+    #[new(value = "64")]
+    pub max_games: usize,
+
+    /// Ratelimit of every ratelimited request kind not in [Self::ratelimits]
+    #[new(value = "RateLimit::new(5, 4.0)")]
+    pub default_ratelimit: RateLimit,
+
+    /// Map of [ClientRequest] kinds to their ratelimit, each connection has its own bucket for every kind
+    #[new(value = "default_ratelimits()")]
+    pub ratelimits: BTreeMap<String, RateLimit>,
+
+    /// How many times per second games are updated
+    #[new(value = "20")]
+    pub tick_rate: u32,
+
+    /// Size of the biggest datagram sent, bigger messages are fragmented
+    #[new(value = "DEFAULT_MAX_DATAGRAM")]
+    pub max_datagram: usize,
+
+    /// Whether matches that waited [Self::backfill_wait] without filling up start with AI players in the empty slots
+    #[new(value = "true")]
+    pub backfill_ai: bool,
+
+    /// How long the longest waiting player in the matchmaking queue waits before AI players are added, in seconds
+    #[new(value = "30")]
+    pub backfill_wait: u64,
+
+    /// How long a player whose connection dropped keeps their slot in a started game, in seconds
+    #[new(value = "60")]
+    pub reconnect_grace: u64,
+
+    /// What gets logged, in the `RUST_LOG` format. The `RUST_LOG` environment variable is used instead if set
+    #[new(value = "String::from(\"info\")")]
+    pub log_level: String,
+
+    /// Whether metrics are served at `http://127.0.0.1:<metrics_port>/metrics`, see [crate::metrics]
+    #[new(value = "true")]
+    pub metrics: bool,
+
+    /// Port the metrics are served on, always bound to localhost
+    #[new(value = "9100")]
+    pub metrics_port: u16,
+
+    /// Strikes an address can collect before it is temporarily banned. Malformed packets are 3 strikes and ratelimited requests 1, see [crate::bans]
+    #[new(value = "15")]
+    pub max_strikes: u32,
+
+    /// How long a temporary ban lasts, in seconds
+    #[new(value = "600")]
+    pub temp_ban: u64,
+
+    /// Temporary bans an address can get before its next ban is permanent
+    #[new(value = "3")]
+    pub max_temp_bans: u32,
+
+    /// File bans are saved to, created when the first ban is made. Relative to the config file when set in it
+    #[new(value = "String::from(BANS_PATH)")]
+    pub bans_file: String,
+
+    /// Database file profiles are kept in, created if missing. Relative to the config file when set in it
+    #[new(value = "String::from(PROFILES_PATH)")]
+    pub profiles_file: String,
+}
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig::new()
+    }
+}
+impl ServerConfig {
+    /// Returns the ratelimit of a [ClientRequest] kind
+    pub fn ratelimit(&self, kind: &str) -> RateLimit {
+        self.ratelimits
+            .get(kind)
+            .copied()
+            .unwrap_or(self.default_ratelimit)
+    }
+
+    /// Address of the UDP socket
+    pub fn udp_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.address.parse().unwrap(), self.port)
+    }
+
+    /// Address of the WebSocket listener
+    pub fn ws_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.address.parse().unwrap(), self.ws_port)
+    }
+
+    fn save(&self, path: &str) -> Result<(), ConfigError> {
+        let str = to_string_pretty(self, PrettyConfig::new().struct_names(true))
+            .map_err(|err| ConfigError(format!("Failed to serialize config; {err}")))?;
+        std::fs::write(path, str + "\n")
+            .map_err(|err| ConfigError(format!("Failed to write \"{path}\"; {err}")))
+    }
+
+    /// Loads the config file, creating it with the defaults if it doesn't exist. Unlike the game's config, a file that can't be parsed is an error instead of being replaced
+    fn load(path: &str) -> Result<ServerConfig, ConfigError> {
+        let mut config = if Path::new(path).exists() {
+            let str = std::fs::read_to_string(path)
+                .map_err(|err| ConfigError(format!("Failed to read \"{path}\"; {err}")))?;
+            ron::from_str::<ServerConfig>(&str)
+                .map_err(|err| ConfigError(format!("Invalid config \"{path}\"; {err}")))?
+        } else {
+            let config = ServerConfig::new();
+            config.save(path)?;
+            config
+        };
+
+        // Files named in the config sit next to it, wherever the server is run from
+        config.bans_file = beside(path, &config.bans_file);
+        config.profiles_file = beside(path, &config.profiles_file);
+        Ok(config)
+    }
+
+    /// Applies a single `--option value` from the command line
+    fn apply(&mut self, option: &str, value: &str) -> Result<(), ConfigError> {
+        fn parse<T: std::str::FromStr>(option: &str, value: &str) -> Result<T, ConfigError> {
+            value
+                .parse()
+                .map_err(|_| ConfigError(format!("Invalid value for {option}: \"{value}\"")))
+        }
+
+        match option {
+            "--address" => self.address = value.to_string(),
+            "--port" => self.port = parse(option, value)?,
+            "--ws-port" => self.ws_port = parse(option, value)?,
+            "--max-connections" => self.max_connections = parse(option, value)?,
+            "--max-link-sessions" => self.max_link_sessions = parse(option, value)?,
+            "--max-address-sessions" => self.max_address_sessions = parse(option, value)?,
+            "--max-links" => self.max_links = parse(option, value)?,
+            "--max-address-links" => self.max_address_links = parse(option, value)?,
+            "--max-games" => self.max_games = parse(option, value)?,
+            "--tick-rate" => self.tick_rate = parse(option, value)?,
+            "--max-datagram" => self.max_datagram = parse(option, value)?,
+            "--backfill-ai" => self.backfill_ai = parse(option, value)?,
+            "--backfill-wait" => self.backfill_wait = parse(option, value)?,
+            "--reconnect-grace" => self.reconnect_grace = parse(option, value)?,
+            "--log-level" => self.log_level = value.to_string(),
+            "--metrics" => self.metrics = parse(option, value)?,
+            "--metrics-port" => self.metrics_port = parse(option, value)?,
+            "--max-strikes" => self.max_strikes = parse(option, value)?,
+            "--temp-ban" => self.temp_ban = parse(option, value)?,
+            "--max-temp-bans" => self.max_temp_bans = parse(option, value)?,
+            "--bans-file" => self.bans_file = value.to_string(),
+            "--profiles-file" => self.profiles_file = value.to_string(),
+            _ => return Err(ConfigError(format!("Unknown option: {option}\n\n{USAGE}"))),
+        }
+        Ok(())
+    }
+
+    /// Checks every setting, returning the first one that is invalid
+    fn validate(&self) -> Result<(), ConfigError> {
+        macro_rules! check {
+            ($cond:expr, $($arg:tt)*) => {
+                if !$cond {
+                    return Err(ConfigError(format!($($arg)*)));
+                }
+            };
+        }
+
+        check!(
+            self.address.parse::<IpAddr>().is_ok(),
+            "address must be an IP address, got \"{}\"",
+            self.address
+        );
+        check!(
+            self.port != self.ws_port || self.port == 0,
+            "port and ws_port must be different, both are {}",
+            self.port
+        );
+        check!(
+            self.max_connections > 0,
+            "max_connections must be at least 1"
+        );
+        check!(
+            self.max_link_sessions > 0,
+            "max_link_sessions must be at least 1"
+        );
+        check!(
+            self.max_address_sessions >= self.max_link_sessions,
+            "max_address_sessions must be at least max_link_sessions"
+        );
+        check!(
+            self.max_address_links > 0 && self.max_links >= self.max_address_links,
+            "max_address_links must be at least 1 and max_links at least max_address_links"
+        );
+        check!(self.max_games > 0, "max_games must be at least 1");
+        check!(self.max_strikes > 0, "max_strikes must be at least 1");
+        for (kind, limit) in std::iter::once(("default", &self.default_ratelimit)).chain(
+            self.ratelimits
+                .iter()
+                .map(|(kind, limit)| (kind.as_str(), limit)),
+        ) {
+            check!(
+                kind == "default" || ClientRequest::KINDS.contains(&kind),
+                "ratelimits has an unknown request kind \"{kind}\""
+            );
+            check!(
+                limit.burst > 0 && limit.per_second.is_finite() && limit.per_second > 0.0,
+                "ratelimit of {kind} needs a burst of at least 1 and a positive per_second"
+            );
+        }
+        check!(
+            (1..=MAX_TICK_RATE).contains(&self.tick_rate),
+            "tick_rate must be between 1 and {MAX_TICK_RATE}, got {}",
+            self.tick_rate
+        );
+        check!(
+            (MIN_DATAGRAM..=MAX_UDP_PAYLOAD).contains(&self.max_datagram),
+            "max_datagram must be between {MIN_DATAGRAM} and {MAX_UDP_PAYLOAD}, got {}",
+            self.max_datagram
+        );
+        check!(
+            EnvFilter::try_new(&self.log_level).is_ok(),
+            "log_level must be a valid filter, got \"{}\"",
+            self.log_level
+        );
+        check!(
+            !self.metrics || self.metrics_port != self.ws_port || self.metrics_port == 0,
+            "metrics_port and ws_port must be different, both are {}",
+            self.metrics_port
+        );
+        Ok(())
+    }
+}
+
+/// Why the config couldn't be loaded, printed before the server exits
+#[derive(Debug)]
+pub struct ConfigError(String);
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ConfigError {}
+
+static CONFIG: OnceLock<ServerConfig> = OnceLock::new();
+
+/// Resolves a path from the config file against the directory the config file is in, absolute paths are kept as they are
+fn beside(config_path: &str, file: &str) -> String {
+    let dir = Path::new(config_path).parent().unwrap_or(Path::new(""));
+    dir.join(file).to_string_lossy().into_owned()
+}
+
+/// Loads the config file given by `--config`, or the default one, applies the rest of the arguments on top and validates the result. Returns `Ok(None)` if only `--help` was asked for
+pub fn init(
+    args: impl Iterator<Item = String>,
+) -> Result<Option<&'static ServerConfig>, ConfigError> {
+    let mut path = CONFIG_PATH.to_string();
+    let mut overrides = vec![];
+
+    let mut args = args.skip(1);
+    while let Some(option) = args.next() {
+        if option == "--help" || option == "-h" {
+            println!("{USAGE}");
+            return Ok(None);
+        }
+
+        let value = args
+            .next()
+            .ok_or_else(|| ConfigError(format!("Missing value for {option}\n\n{USAGE}")))?;
+        if option == "--config" {
+            path = value;
+        } else {
+            overrides.push((option, value));
+        }
+    }
+
+    let mut loaded = ServerConfig::load(&path)?;
+    for (option, value) in overrides {
+        loaded.apply(&option, &value)?;
+    }
+    loaded.validate()?;
+
+    if CONFIG.set(loaded).is_err() {
+        panic!("Config already initialized");
+    }
+    Ok(Some(config()))
+}
+
+/// Returns the global [ServerConfig], panics if [init] wasn't called
+pub fn config() -> &'static ServerConfig {
+    CONFIG.get().expect("Config not initialized")
+}
@@ -0,0 +1,198 @@
+//! Admin commands typed into the server's stdin. Stops quietly if stdin is closed, so the server can still run without a terminal
+
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use ak_server::types_server::ServerMessage;
+use ak_server::util::now;
+use colored::Colorize;
+use tokio::io::{stdin, AsyncBufReadExt, BufReader};
+
+use crate::bans::{ban, banned, unban};
+use crate::games::{end_games, game_details};
+use crate::session::{kick, SESSIONS};
+use crate::transport::{ip, send};
+
+/// How long clients are given to receive the shutdown notice before the server exits, UDP clients might need a resend or two
+const SHUTDOWN_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest games are waited on to save their players' stats before the server exits anyway
+const SAVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const HELP: &str = "Commands:
+    help                      Print this message
+    connections               List connections with their username, address and game
+    games                     List games with their players and spectators
+    kick <uuid> [reason]      End a connection's session and take it out of its game
+    ban <uuid> [reason]       Kick every connection from the same address and ban it permanently
+    unban <ip>                Lift a ban
+    bans                      List banned addresses with their reason and when they end
+    say <message>             Send an announcement to every connection
+    shutdown                  Tell every connection the server is stopping, end every game and exit";
+
+/// Reads commands from stdin until it closes, runs forever otherwise
+pub async fn run_console() {
+    let mut lines = BufReader::new(stdin()).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+        let args = args.trim();
+        match command {
+            "help" => println!("{HELP}"),
+            "connections" => list_connections(),
+            "games" => list_games(),
+            "kick" => kick_command(args, false),
+            "ban" => kick_command(args, true),
+            "unban" => match args.parse::<IpAddr>() {
+                Ok(ip) if unban(ip) => println!("{}", format!("Unbanned {ip}").green()),
+                Ok(ip) => println!("{}", format!("{ip} isn't banned").red()),
+                Err(_) => println!("{}", "Usage: unban <ip>".red()),
+            },
+            "bans" => {
+                let now = now();
+                for (ip, ban) in banned() {
+                    let ends = ban.until.map_or("never".to_string(), |until| {
+                        format!("in {}s", until.saturating_sub(now) / 1000)
+                    });
+                    println!("{ip}  ends: {ends}  {}", ban.reason);
+                }
+            }
+            "say" => {
+                if args.is_empty() {
+                    println!("{}", "Usage: say <message>".red());
+                    continue;
+                }
+                let count = broadcast(&ServerMessage::Announcement(args.to_string()));
+                println!("{}", format!("Sent to {count} connections").green());
+            }
+            "shutdown" => shutdown().await,
+            _ => println!("{}", format!("Unknown command: {command}, try help").red()),
+        }
+    }
+}
+
+fn list_connections() {
+    // Copied out so the sessions aren't locked while the transports are
+    let sessions: Vec<_> = SESSIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(uuid, session)| {
+            (
+                *uuid,
+                session.username.clone(),
+                session.game,
+                session.last_seen,
+            )
+        })
+        .collect();
+
+    println!("{} connections", sessions.len());
+    for (uuid, username, game, last_seen) in sessions {
+        let address = ip(uuid).map_or("-".to_string(), |ip| ip.to_string());
+        let game = game.map_or("-".to_string(), |game_uuid| game_uuid.to_string());
+        println!(
+            "{uuid}  {username}  {address}  game: {game}  idle: {}s",
+            now().saturating_sub(last_seen) / 1000
+        );
+    }
+}
+
+fn list_games() {
+    let games = game_details();
+    println!("{} games", games.len());
+    for (summary, lobby) in games {
+        let Some(lobby) = lobby else {
+            println!("{}  players: {}", summary.uuid, summary.players);
+            continue;
+        };
+
+        let state = if lobby.started { "started" } else { "lobby" };
+        let code = lobby.code.as_deref().unwrap_or("public");
+        println!("{}  {state}  {code}  host: {:?}", summary.uuid, lobby.host);
+        for player in lobby.players {
+            let kind = match (player.ai, player.connected) {
+                (true, _) => " (AI)",
+                (false, false) => " (dropped)",
+                (false, true) => "",
+            };
+            println!(
+                "    {:?}  {}  {}{kind}",
+                player.color, player.uuid, player.username
+            );
+        }
+        for spectator in lobby.spectators {
+            println!("    Spectator  {}  {}", spectator.uuid, spectator.username);
+        }
+    }
+}
+
+/// Handles `kick` and `ban`, banning kicks every connection from the address
+fn kick_command(args: &str, banning: bool) {
+    let (uuid, reason) = args.split_once(' ').unwrap_or((args, ""));
+    let Ok(uuid) = uuid.parse::<u64>() else {
+        let usage = if banning { "ban" } else { "kick" };
+        println!("{}", format!("Usage: {usage} <uuid> [reason]").red());
+        return;
+    };
+    let reason = match (reason.trim(), banning) {
+        ("", true) => "Banned from the server",
+        ("", false) => "Kicked from the server",
+        (reason, _) => reason,
+    };
+
+    if !banning {
+        if kick(uuid, reason) {
+            println!("{}", format!("Kicked {uuid}").green());
+        } else {
+            println!("{}", format!("No connection {uuid}").red());
+        }
+        return;
+    }
+
+    let Some(address) = ip(uuid) else {
+        println!("{}", format!("No connection {uuid}").red());
+        return;
+    };
+    let kicked = ban(address, reason, None);
+    println!(
+        "{}",
+        format!("Banned {address}, kicked {kicked} connections").green()
+    );
+}
+
+/// Sends a message to every connection with a session, returns how many it was sent to
+fn broadcast(message: &ServerMessage) -> usize {
+    let payload = rmp_serde::to_vec(message).unwrap();
+    let uuids: Vec<u64> = SESSIONS.lock().unwrap().keys().copied().collect();
+    for uuid in uuids.iter() {
+        send(*uuid, payload.clone());
+    }
+    uuids.len()
+}
+
+/// Tells every connection the server is stopping, ends every game so their stats are saved, gives the notice time to arrive and exits
+async fn shutdown() {
+    let count = broadcast(&ServerMessage::Closed(
+        "The server is shutting down".to_string(),
+    ));
+    println!(
+        "{}",
+        format!("Shutting down, notified {count} connections").red()
+    );
+
+    let started = Instant::now();
+    let unfinished = end_games(SAVE_TIMEOUT).await;
+    if unfinished > 0 {
+        println!(
+            "{}",
+            format!("{unfinished} games didn't finish saving in time").red()
+        );
+    }
+    tokio::time::sleep(SHUTDOWN_DELAY.saturating_sub(started.elapsed())).await;
+    std::process::exit(0);
+}
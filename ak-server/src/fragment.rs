@@ -0,0 +1,289 @@
+//! Splits messages that don't fit in a single datagram into fragments, and puts them back together on the other side
+
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+
+/// Default size of the biggest datagram sent, small enough to avoid IP fragmentation on most networks
+pub const DEFAULT_MAX_DATAGRAM: usize = 1200;
+
+/// Biggest possible UDP payload, used for receive buffers so no datagram is ever cut off
+pub const MAX_UDP_PAYLOAD: usize = 65_507;
+
+/// Bytes of each datagram reserved for the [Frame] around the data
+const FRAME_OVERHEAD: usize = 32;
+
+/// Most fragments a single message can be split into
+const MAX_FRAGMENTS: usize = 1024;
+
+/// Most messages that can be partially received at once, the oldest is dropped to make room
+const MAX_PARTIAL: usize = 8;
+
+/// How much of incoming messages a [Fragmenter] buffers while putting them back together
+#[derive(Debug, Clone, Copy)]
+pub struct ReassemblyLimits {
+    /// Most messages that can be partially received at once, the oldest is dropped to make room
+    pub partial: usize,
+    /// Most fragments an incoming message can have, messages with more are ignored
+    pub fragments: usize,
+}
+impl ReassemblyLimits {
+    /// Limits for a peer that is trusted with the biggest messages
+    pub const FULL: ReassemblyLimits = ReassemblyLimits {
+        partial: MAX_PARTIAL,
+        fragments: MAX_FRAGMENTS,
+    };
+
+    /// Limits for a peer without a session, anything before `Connect` fits in a datagram or two. Addresses are easy to spoof, so this is all a fake one costs
+    pub const SESSIONLESS: ReassemblyLimits = ReassemblyLimits {
+        partial: 1,
+        fragments: 4,
+    };
+}
+
+/// How long an incomplete message is kept before being dropped, in milliseconds
+pub const REASSEMBLY_TIMEOUT: u64 = 5_000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Frame {
+    /// A message that fit in a single datagram
+    Whole(#[serde(with = "serde_bytes")] Vec<u8>),
+    /// One part of a bigger message
+    Fragment {
+        message: u32,
+        index: u16,
+        count: u16,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+}
+
+/// A message still waiting for some of its fragments
+struct Partial {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    started_at: u64,
+}
+
+/// Splits outgoing messages into datagrams of at most `max_datagram` bytes, and joins incoming fragments back into messages
+///
+/// Times are unix timestamps in milliseconds
+pub struct Fragmenter {
+    max_datagram: usize,
+    next_message: u32,
+    partial: FxHashMap<u32, Partial>,
+    limits: ReassemblyLimits,
+}
+impl Fragmenter {
+    pub fn new(max_datagram: usize) -> Fragmenter {
+        Fragmenter {
+            max_datagram,
+            next_message: 0,
+            partial: FxHashMap::default(),
+            limits: ReassemblyLimits::FULL,
+        }
+    }
+
+    /// Changes how much of incoming messages is buffered, messages already over the new limits are dropped
+    pub fn set_limits(&mut self, limits: ReassemblyLimits) {
+        self.limits = limits;
+        self.partial
+            .retain(|_, partial| partial.fragments.len() <= limits.fragments);
+        while self.partial.len() > limits.partial {
+            self.drop_oldest();
+        }
+    }
+
+    fn drop_oldest(&mut self) {
+        if let Some(oldest) = self
+            .partial
+            .iter()
+            .min_by_key(|(_, partial)| partial.started_at)
+            .map(|(id, _)| *id)
+        {
+            self.partial.remove(&oldest);
+        }
+    }
+
+    /// Splits a message into serialized [Frame]'s ready to be sent. Returns `None` if the message is too big to ever be sent
+    pub fn split(&mut self, message: &[u8]) -> Option<Vec<Vec<u8>>> {
+        let chunk_size = self.max_datagram.saturating_sub(FRAME_OVERHEAD).max(1);
+        if message.len() <= chunk_size {
+            let frame = Frame::Whole(message.to_vec());
+            return Some(vec![rmp_serde::to_vec(&frame).unwrap()]);
+        }
+
+        let count = message.len().div_ceil(chunk_size);
+        if count > MAX_FRAGMENTS {
+            return None;
+        }
+
+        let id = self.next_message;
+        self.next_message = self.next_message.wrapping_add(1);
+
+        let frames = message
+            .chunks(chunk_size)
+            .enumerate()
+            .map(|(index, data)| {
+                let frame = Frame::Fragment {
+                    message: id,
+                    index: index as u16,
+                    count: count as u16,
+                    data: data.to_vec(),
+                };
+                rmp_serde::to_vec(&frame).unwrap()
+            })
+            .collect();
+        Some(frames)
+    }
+
+    /// Handles a received datagram, returning the whole message once every fragment has arrived. Invalid fragments are ignored
+    pub fn join(
+        &mut self,
+        raw: &[u8],
+        now: u64,
+    ) -> Result<Option<Vec<u8>>, rmp_serde::decode::Error> {
+        let (message, index, count, data) = match rmp_serde::from_slice(raw)? {
+            Frame::Whole(data) => return Ok(Some(data)),
+            Frame::Fragment {
+                message,
+                index,
+                count,
+                data,
+            } => (message, index as usize, count as usize, data),
+        };
+
+        if count == 0 || count > self.limits.fragments || index >= count {
+            return Ok(None);
+        }
+
+        // Forget messages that will never be completed
+        self.partial
+            .retain(|_, partial| partial.started_at + REASSEMBLY_TIMEOUT > now);
+        if !self.partial.contains_key(&message) && self.partial.len() >= self.limits.partial {
+            self.drop_oldest();
+        }
+
+        let partial = self.partial.entry(message).or_insert_with(|| Partial {
+            fragments: vec![None; count],
+            received: 0,
+            started_at: now,
+        });
+        if partial.fragments.len() != count {
+            return Ok(None);
+        }
+
+        if partial.fragments[index].is_none() {
+            partial.fragments[index] = Some(data);
+            partial.received += 1;
+        }
+
+        if partial.received < count {
+            return Ok(None);
+        }
+
+        let partial = self.partial.remove(&message).unwrap();
+        Ok(Some(
+            partial.fragments.into_iter().flatten().flatten().collect(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragment(message: u32, index: u16, count: u16) -> Vec<u8> {
+        let frame = Frame::Fragment {
+            message,
+            index,
+            count,
+            data: vec![index as u8],
+        };
+        rmp_serde::to_vec(&frame).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut sender = Fragmenter::new(100);
+        let mut receiver = Fragmenter::new(100);
+        let message: Vec<u8> = (0..5_000).map(|i| i as u8).collect();
+
+        let frames = sender.split(&message).unwrap();
+        assert!(frames.len() > 50);
+        let (last, rest) = frames.split_last().unwrap();
+        // Fragments can arrive in any order
+        for frame in rest.iter().rev() {
+            assert_eq!(receiver.join(frame, 0).unwrap(), None);
+        }
+        assert_eq!(receiver.join(last, 0).unwrap(), Some(message));
+        assert!(receiver.partial.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_fragments() {
+        let mut fragmenter = Fragmenter::new(100);
+        assert_eq!(fragmenter.join(&fragment(0, 0, 0), 0).unwrap(), None);
+        assert_eq!(fragmenter.join(&fragment(0, 2, 2), 0).unwrap(), None);
+        assert!(fragmenter.partial.is_empty());
+    }
+
+    #[test]
+    fn ignores_mismatched_count() {
+        let mut fragmenter = Fragmenter::new(100);
+        assert_eq!(fragmenter.join(&fragment(0, 0, 2), 0).unwrap(), None);
+        assert_eq!(fragmenter.join(&fragment(0, 1, 3), 0).unwrap(), None);
+        assert_eq!(
+            fragmenter.join(&fragment(0, 1, 2), 0).unwrap(),
+            Some(vec![0, 1])
+        );
+    }
+
+    #[test]
+    fn drops_expired_messages() {
+        let mut fragmenter = Fragmenter::new(100);
+        assert_eq!(fragmenter.join(&fragment(0, 0, 2), 0).unwrap(), None);
+        // The first fragment is forgotten, so the message starts over
+        assert_eq!(
+            fragmenter
+                .join(&fragment(0, 1, 2), REASSEMBLY_TIMEOUT)
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            fragmenter
+                .join(&fragment(0, 0, 2), REASSEMBLY_TIMEOUT)
+                .unwrap(),
+            Some(vec![0, 1])
+        );
+    }
+
+    #[test]
+    fn sessionless_limits() {
+        let mut fragmenter = Fragmenter::new(100);
+        fragmenter.set_limits(ReassemblyLimits::SESSIONLESS);
+
+        let fragments = ReassemblyLimits::SESSIONLESS.fragments as u16 + 1;
+        assert_eq!(
+            fragmenter.join(&fragment(0, 0, fragments), 0).unwrap(),
+            None
+        );
+        assert!(fragmenter.partial.is_empty());
+
+        // Only one message is buffered, a newer one replaces it
+        assert_eq!(fragmenter.join(&fragment(1, 0, 2), 0).unwrap(), None);
+        assert_eq!(fragmenter.join(&fragment(2, 0, 2), 1).unwrap(), None);
+        assert_eq!(fragmenter.partial.len(), 1);
+        assert!(fragmenter.partial.contains_key(&2));
+    }
+
+    #[test]
+    fn split_rejects_huge_messages() {
+        // Every fragment carries a single byte
+        let mut fragmenter = Fragmenter::new(FRAME_OVERHEAD + 1);
+        assert_eq!(
+            fragmenter.split(&[0; MAX_FRAGMENTS]).unwrap().len(),
+            MAX_FRAGMENTS
+        );
+        assert_eq!(fragmenter.split(&[0; MAX_FRAGMENTS + 1]), None);
+    }
+}
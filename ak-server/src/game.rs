@@ -1,24 +1,87 @@
-use std::sync::Mutex;
+use std::hash::{Hash, Hasher};
 
 use derive_new::new;
-use lazy_static::lazy_static;
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashSet, FxHasher};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::hashmap;
-use crate::types_game::{ServerMap, ServerPlayer};
+use crate::astar::{astar, walkable};
+use crate::types_game::{
+    footprint, BuildingKind, Color, Direction, OreKind, ServerBuilding, ServerMap, ServerOrePatch,
+    ServerPlayer, ServerWorker, Sprite, Texture, TilePos, TILE_SIZE,
+};
+use crate::types_server::{ErrorCode, GameSummary, Snapshot};
 
-lazy_static! {
-    /// Map of every game to its id
-    pub static ref GAMES: Mutex<FxHashMap<Uuid, Game>> = Mutex::new(hashmap! {});
-    /// Map of every socket connection to their game
-    pub static ref CONN_GAMES: Mutex<FxHashMap<u64, Uuid>> = Mutex::new(hashmap! {});
+/// Most players a game can have, one for each [Color]
+pub const MAX_PLAYERS: usize = Color::ALL.len();
+
+/// Most workers a player can start with, any more wouldn't fit next to their spawn point
+pub const MAX_STARTING_WORKERS: u32 = 6;
+
+/// How far a worker can be from an ore patch and still mine it, in pixels
+const MINING_REACH: f32 = 10.0;
+
+/// Frames per second of worker spritesheets, same as the client's
+const ANIMATION_FPS: f32 = 12.0;
+
+/// How often AI players look for something for their idle workers to do, in ticks
+const AI_THINK_TICKS: u64 = 20;
+
+/// Ticks simulated each [Turn] of a lockstep game
+pub const TURN_TICKS: u64 = 2;
+
+/// How players of a game are kept in sync
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum GameMode {
+    /// The server simulates the game and sends snapshots of it
+    #[default]
+    Snapshots,
+    /// The server only sends each turn's events, every client simulates the game itself with [Game::apply_turn]
+    Lockstep,
+}
+
+/// Something that happened between two turns of a lockstep game, applied in the order they happened on the server
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum TurnEvent {
+    Join(u64),
+    Leave(u64),
+    /// A player that dropped took their slot back from a new connection, `(old, new)` uuids
+    Reconnect(u64, u64),
+    /// Only commands the server accepted are sent, so they are accepted everywhere
+    Command(u64, Command),
+}
+
+/// Everything that happened in a lockstep game since the last turn
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Turn {
+    /// Number of the turn, the first is 1
+    pub turn: u64,
+    pub events: Vec<TurnEvent>,
+}
+
+/// What the host of a game can change before it starts
+#[derive(new, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GameSettings {
+    /// Seed the map is generated from, see [ServerMap::generate]
+    #[new(value = "0")]
+    pub seed: u64,
+    /// Workers each player starts with, at most [MAX_STARTING_WORKERS]
+    #[new(value = "4")]
+    pub starting_workers: u32,
+}
+impl GameSettings {
+    pub fn valid(&self) -> bool {
+        (1..=MAX_STARTING_WORKERS).contains(&self.starting_workers)
+    }
 }
 
-pub fn in_game(uuid: u64) -> bool {
-    let games = CONN_GAMES.lock().unwrap();
-    games.contains_key(&uuid)
+/// An action a player takes in a game, see [Game::command]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Command {
+    MoveWorker { worker: u32, goal: TilePos },
+    AssignOre { worker: u32, ore: usize },
+    PlaceBuilding { kind: BuildingKind, pos: TilePos },
+    Cancel { worker: u32 },
 }
 
 #[derive(new, Clone, Serialize, Deserialize)]
@@ -26,8 +89,600 @@ pub struct Game {
     #[new(value = "Uuid::new_v4()")]
     pub uuid: Uuid,
 
+    #[new(value = "vec![]")]
     pub players: Vec<ServerPlayer>,
 
-    #[new(value = "ServerMap::random()")]
+    #[new(value = "ServerMap::generate(0)")]
     pub map: ServerMap,
+
+    /// What the map and players were set up with, see [Game::set_settings]
+    #[new(value = "GameSettings::new()")]
+    pub settings: GameSettings,
+
+    /// How many times the game has been ticked
+    #[new(value = "0")]
+    pub tick: u64,
+
+    /// Id the next worker gets, see [ServerWorker::id]
+    #[new(value = "0")]
+    pub next_worker_id: u32,
+
+    #[new(value = "GameMode::Snapshots")]
+    pub mode: GameMode,
+
+    /// Games made with a lobby wait in it until the host starts them, they aren't ticked until then
+    #[new(value = "true")]
+    pub started: bool,
+}
+impl Game {
+    /// Adds a player with the next free [Color] and their starting workers, returns `None` if the game is full
+    pub fn add_player(&mut self, uuid: u64) -> Option<Color> {
+        let color = Color::ALL
+            .into_iter()
+            .find(|color| self.players.iter().all(|player| player.color != *color))?;
+
+        let player = self.spawn_player(uuid, color);
+        self.players.push(player);
+        Some(color)
+    }
+
+    /// Makes a player with their starting workers at the color's spawn point
+    fn spawn_player(&mut self, uuid: u64, color: Color) -> ServerPlayer {
+        let mut player = ServerPlayer::new(uuid, color);
+        player.team = Color::ALL.iter().position(|other| *other == color).unwrap() as u8;
+        let (x, y) = self.spawn_point(color);
+        for i in 0..self.settings.starting_workers {
+            let pos = (x + i as f32 * TILE_SIZE, y);
+            player
+                .workers
+                .push(ServerWorker::new(self.next_worker_id, color, pos));
+            self.next_worker_id += 1;
+        }
+        player
+    }
+
+    /// Regenerates the map from the settings and spawns every player again, keeping their colors. Only meant for games that haven't started
+    pub fn set_settings(&mut self, settings: GameSettings) {
+        self.settings = settings;
+        self.map = ServerMap::generate(settings.seed);
+        self.next_worker_id = 0;
+
+        let players = std::mem::take(&mut self.players);
+        for old in players {
+            let mut player = self.spawn_player(old.uuid, old.color);
+            player.ai = old.ai;
+            player.team = old.team;
+            self.players.push(player);
+        }
+    }
+
+    /// Removes a player from the game, does nothing if they aren't in it
+    pub fn remove_player(&mut self, uuid: u64) {
+        self.players.retain(|player| player.uuid != uuid);
+    }
+
+    /// Hands a player's slot, with their workers, buildings and ores, to a new uuid. Returns the slot's color, or `None` if there is no player `old`
+    pub fn rekey_player(&mut self, old: u64, new: u64) -> Option<Color> {
+        let player = self.players.iter_mut().find(|player| player.uuid == old)?;
+        player.uuid = new;
+        Some(player.color)
+    }
+
+    /// Adds an AI player with the next free [Color], returns `None` if the game is full
+    pub fn add_ai(&mut self) -> Option<Color> {
+        let color = self.add_player(rand::random())?;
+        self.players.last_mut().unwrap().ai = true;
+        Some(color)
+    }
+
+    pub fn has_player(&self, uuid: u64) -> bool {
+        self.players.iter().any(|player| player.uuid == uuid)
+    }
+
+    /// Whether anyone other than AI players is left
+    pub fn has_people(&self) -> bool {
+        self.players.iter().any(|player| !player.ai)
+    }
+
+    pub fn summary(&self) -> GameSummary {
+        GameSummary {
+            uuid: self.uuid,
+            players: self.players.len() as u8,
+            started: self.started,
+        }
+    }
+
+    /// The state sent to every player after each tick
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            tick: self.tick,
+            players: self.players.clone(),
+            ores: self.map.ores.clone(),
+        }
+    }
+
+    /// Where the workers of a color start, each color gets a different corner of the map
+    fn spawn_point(&self, color: Color) -> (f32, f32) {
+        let right = (self.map.width as f32 - 8.0) * TILE_SIZE;
+        let bottom = (self.map.height as f32 - 3.0) * TILE_SIZE;
+        match color {
+            Color::Blue => (3.0 * TILE_SIZE, 12.0 * TILE_SIZE),
+            Color::Red => (right, 12.0 * TILE_SIZE),
+            Color::Green => (3.0 * TILE_SIZE, bottom),
+            Color::Yellow => (right, bottom),
+        }
+    }
+
+    /// Every tile workers can't walk on because of an ore patch or building
+    pub fn blocked_tiles(&self) -> FxHashSet<TilePos> {
+        let ores = self.map.ores.iter().flat_map(|ore| ore.tiles());
+        let buildings = self
+            .players
+            .iter()
+            .flat_map(|player| player.buildings.iter())
+            .flat_map(|building| building.tiles());
+        ores.chain(buildings).collect()
+    }
+
+    /// Checks a command from a player and applies it, workers are looked up by id in the player's own workers so players can only command their own
+    pub fn command(&mut self, uuid: u64, command: Command) -> Result<(), ErrorCode> {
+        let blocked = self.blocked_tiles();
+        let worker_tiles: FxHashSet<TilePos> = self
+            .players
+            .iter()
+            .flat_map(|player| player.workers.iter())
+            .flat_map(|worker| worker.tiles())
+            .collect();
+
+        let player = self
+            .players
+            .iter_mut()
+            .find(|player| player.uuid == uuid)
+            .ok_or(ErrorCode::NotInGame)?;
+
+        match command {
+            Command::MoveWorker { worker, goal } => {
+                let worker = player
+                    .workers
+                    .iter_mut()
+                    .find(|w| w.id == worker)
+                    .ok_or(ErrorCode::WorkerNotFound)?;
+                if !walkable(goal, &self.map, &blocked) {
+                    return Err(ErrorCode::Unreachable);
+                }
+                let path = astar(worker.tile(), goal, &self.map, &blocked)
+                    .ok_or(ErrorCode::Unreachable)?;
+
+                worker.path = Some(path);
+                worker.ore = None;
+            }
+            Command::AssignOre { worker, ore } => {
+                let worker = player
+                    .workers
+                    .iter_mut()
+                    .find(|w| w.id == worker)
+                    .ok_or(ErrorCode::WorkerNotFound)?;
+                let patch = self.map.ores.get(ore).ok_or(ErrorCode::OreNotFound)?;
+
+                worker.path = if in_reach(worker, patch) {
+                    None
+                } else {
+                    Some(
+                        path_to_ore(worker, patch, &self.map, &blocked)
+                            .ok_or(ErrorCode::Unreachable)?,
+                    )
+                };
+                worker.ore = Some(ore);
+            }
+            Command::PlaceBuilding { kind, pos } => {
+                // The position comes from the client, so the building has to fit on the map before its tiles can be walked
+                let (width, height) = kind.size();
+                let fits = pos
+                    .0
+                    .checked_add(width)
+                    .is_some_and(|right| right as usize <= self.map.width)
+                    && pos
+                        .1
+                        .checked_add(height)
+                        .is_some_and(|bottom| bottom as usize <= self.map.height);
+                if !fits {
+                    return Err(ErrorCode::BuildingBlocked);
+                }
+
+                let building = ServerBuilding { kind, pos };
+                if building.tiles().any(|tile| {
+                    !walkable(tile, &self.map, &blocked) || worker_tiles.contains(&tile)
+                }) {
+                    return Err(ErrorCode::BuildingBlocked);
+                }
+
+                let affordable = kind
+                    .cost()
+                    .iter()
+                    .all(|(ore, amount)| player.ores.get(ore).copied().unwrap_or(0) >= *amount);
+                if !affordable {
+                    return Err(ErrorCode::CannotAfford);
+                }
+                for (ore, amount) in kind.cost() {
+                    *player.ores.entry(*ore).or_insert(0) -= amount;
+                }
+
+                let tiles: FxHashSet<TilePos> = building.tiles().collect();
+                player.buildings.push(building);
+
+                // Paths through the building aren't walkable anymore, workers with an ore find a new one on their own
+                for worker in self
+                    .players
+                    .iter_mut()
+                    .flat_map(|player| player.workers.iter_mut())
+                {
+                    let crosses = worker.path.as_ref().is_some_and(|path| {
+                        path.iter().any(|pos| {
+                            tiles
+                                .contains(&((pos.0 / TILE_SIZE) as u32, (pos.1 / TILE_SIZE) as u32))
+                        })
+                    });
+                    if crosses {
+                        worker.path = None;
+                    }
+                }
+            }
+            Command::Cancel { worker } => {
+                let worker = player
+                    .workers
+                    .iter_mut()
+                    .find(|w| w.id == worker)
+                    .ok_or(ErrorCode::WorkerNotFound)?;
+                worker.path = None;
+                worker.ore = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies a turn's events then simulates [TURN_TICKS] ticks of `dt` seconds, the server and every lockstep client do this the same way
+    pub fn apply_turn(&mut self, turn: &Turn, dt: f32) {
+        for event in turn.events.iter() {
+            match *event {
+                TurnEvent::Join(uuid) => {
+                    self.add_player(uuid);
+                }
+                TurnEvent::Leave(uuid) => self.remove_player(uuid),
+                TurnEvent::Reconnect(old, new) => {
+                    self.rekey_player(old, new);
+                }
+                TurnEvent::Command(uuid, command) => {
+                    let _ = self.command(uuid, command);
+                }
+            }
+        }
+        self.step_turn(dt);
+    }
+
+    /// Simulates [TURN_TICKS] ticks of `dt` seconds
+    pub fn step_turn(&mut self, dt: f32) {
+        for _ in 0..TURN_TICKS {
+            self.update(dt);
+        }
+    }
+
+    /// Number of the last turn simulated
+    pub fn turn(&self) -> u64 {
+        self.tick / TURN_TICKS
+    }
+
+    /// Hash of everything that is simulated, lockstep clients send theirs so desyncs are caught. Floats are hashed by their bits, so any difference counts
+    pub fn checksum(&self) -> u64 {
+        let mut hasher = FxHasher::default();
+        self.tick.hash(&mut hasher);
+        self.next_worker_id.hash(&mut hasher);
+
+        for player in self.players.iter() {
+            player.uuid.hash(&mut hasher);
+            player.color.hash(&mut hasher);
+            player.team.hash(&mut hasher);
+            player.buildings.hash(&mut hasher);
+
+            let mut ores: Vec<_> = player.ores.iter().collect();
+            ores.sort_by_key(|(kind, _)| **kind as u8);
+            ores.hash(&mut hasher);
+
+            for worker in player.workers.iter() {
+                worker.id.hash(&mut hasher);
+                worker.pos.0.to_bits().hash(&mut hasher);
+                worker.pos.1.to_bits().hash(&mut hasher);
+                worker.ore.hash(&mut hasher);
+                worker.mining.hash(&mut hasher);
+                worker.mine_cooldown.to_bits().hash(&mut hasher);
+                for point in worker.path.iter().flatten() {
+                    point.0.to_bits().hash(&mut hasher);
+                    point.1.to_bits().hash(&mut hasher);
+                }
+            }
+        }
+
+        for ore in self.map.ores.iter() {
+            ore.remaining.hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    /// Sends every idle worker of AI players to mine the closest ore patch that has anything left, through the same commands players use
+    fn update_ai(&mut self) {
+        let mut commands = vec![];
+        for player in self.players.iter().filter(|player| player.ai) {
+            for worker in player.workers.iter() {
+                if worker.ore.is_some() || worker.path.is_some() {
+                    continue;
+                }
+
+                let distance = |ore: &ServerOrePatch| {
+                    let (x, y) = worker.tile();
+                    ore.pos.0.abs_diff(x) + ore.pos.1.abs_diff(y)
+                };
+                let closest = self
+                    .map
+                    .ores
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, ore)| ore.remaining > 0)
+                    .min_by_key(|(_, ore)| distance(ore));
+                if let Some((ore, _)) = closest {
+                    let command = Command::AssignOre {
+                        worker: worker.id,
+                        ore,
+                    };
+                    commands.push((player.uuid, command));
+                }
+            }
+        }
+
+        for (uuid, command) in commands {
+            let _ = self.command(uuid, command);
+        }
+    }
+
+    /// Advances the game by `dt` seconds
+    pub fn update(&mut self, dt: f32) {
+        self.tick += 1;
+        if self.tick.is_multiple_of(AI_THINK_TICKS) {
+            self.update_ai();
+        }
+
+        let blocked = self.blocked_tiles();
+        for player in self.players.iter_mut() {
+            for worker in player.workers.iter_mut() {
+                if let Some(kind) = update_ore(worker, &mut self.map, &blocked, dt) {
+                    *player.ores.entry(kind).or_insert(0) += 1;
+                    player.gathered += 1;
+                }
+
+                let moved = update_path(worker, dt);
+                update_sprite(worker, player.color, moved, dt);
+            }
+        }
+    }
+}
+
+/// Whether a worker is close enough to an ore patch to mine it
+fn in_reach(worker: &ServerWorker, ore: &ServerOrePatch) -> bool {
+    let (width, height) = ore.kind.size();
+    let left = ore.pos.0 as f32 * TILE_SIZE - MINING_REACH;
+    let top = ore.pos.1 as f32 * TILE_SIZE - MINING_REACH;
+    let right = (ore.pos.0 + width) as f32 * TILE_SIZE + MINING_REACH;
+    let bottom = (ore.pos.1 + height) as f32 * TILE_SIZE + MINING_REACH;
+
+    worker.pos.0 < right
+        && worker.pos.0 + TILE_SIZE > left
+        && worker.pos.1 < bottom
+        && worker.pos.1 + TILE_SIZE > top
+}
+
+/// Returns a path to the closest free tile around an ore patch, or `None` if there is none
+fn path_to_ore(
+    worker: &ServerWorker,
+    ore: &ServerOrePatch,
+    map: &ServerMap,
+    blocked: &FxHashSet<TilePos>,
+) -> Option<Vec<(f32, f32)>> {
+    let (width, height) = ore.kind.size();
+    let start = worker.tile();
+    let top_left = (ore.pos.0.saturating_sub(1), ore.pos.1.saturating_sub(1));
+    let closest = footprint(top_left, (width + 2, height + 2))
+        .filter(|tile| walkable(*tile, map, blocked))
+        .min_by_key(|tile| tile.0.abs_diff(start.0).pow(2) + tile.1.abs_diff(start.1).pow(2))?;
+
+    astar(start, closest, map, blocked)
+}
+
+/// Mines the worker's ore if it is in reach, otherwise paths to it. Returns the kind of ore if a piece was mined
+fn update_ore(
+    worker: &mut ServerWorker,
+    map: &mut ServerMap,
+    blocked: &FxHashSet<TilePos>,
+    dt: f32,
+) -> Option<OreKind> {
+    worker.mining = false;
+    worker.mine_cooldown = (worker.mine_cooldown - dt).max(0.0);
+
+    let index = worker.ore?;
+    let ore = map.ores.get(index)?;
+
+    if in_reach(worker, ore) {
+        worker.path = None;
+        worker.mining = true;
+        if worker.mine_cooldown > 0.0 || ore.remaining == 0 {
+            return None;
+        }
+
+        let ore = &mut map.ores[index];
+        ore.remaining -= 1;
+        worker.mine_cooldown = ore.kind.cooldown();
+        return Some(ore.kind);
+    }
+
+    if worker.path.is_none() {
+        worker.path = path_to_ore(worker, ore, map, blocked);
+
+        // Give up on ores that can't be reached instead of searching again every tick
+        if worker.path.is_none() {
+            worker.ore = None;
+        }
+    }
+    None
+}
+
+/// Moves the worker along its path, returns how far it moved. Also used by clients to predict their own workers
+pub fn update_path(worker: &mut ServerWorker, dt: f32) -> (f32, f32) {
+    let Some(path) = &mut worker.path else {
+        return (0.0, 0.0);
+    };
+    let Some(next_pos) = path.first().copied() else {
+        worker.path = None;
+        return (0.0, 0.0);
+    };
+
+    let (dx, dy) = (next_pos.0 - worker.pos.0, next_pos.1 - worker.pos.1);
+    let dist = (dx * dx + dy * dy).sqrt();
+    let speed = ServerWorker::SPEED * dt;
+
+    let new_pos = if dist > speed {
+        (
+            worker.pos.0 + dx / dist * speed,
+            worker.pos.1 + dy / dist * speed,
+        )
+    } else {
+        path.remove(0);
+        next_pos
+    };
+
+    let moved = (new_pos.0 - worker.pos.0, new_pos.1 - worker.pos.1);
+    worker.pos = new_pos;
+    moved
+}
+
+/// Turns the worker to face where it moved and advances its animation
+pub fn update_sprite(worker: &mut ServerWorker, color: Color, moved: (f32, f32), dt: f32) {
+    let sign = |x: f32| {
+        if x > 0.0 {
+            1
+        } else if x < 0.0 {
+            -1
+        } else {
+            0
+        }
+    };
+
+    // Diagonals keep the current direction if it is one of the two
+    let keep = |first: Direction, second: Direction| {
+        if worker.direction == first {
+            first
+        } else {
+            second
+        }
+    };
+    worker.direction = match (sign(moved.0), sign(moved.1)) {
+        (1, 0) => Direction::Right,
+        (0, -1) => Direction::Up,
+        (-1, 0) => Direction::Left,
+        (0, 1) => Direction::Down,
+        (1, -1) => keep(Direction::Up, Direction::Right),
+        (-1, -1) => keep(Direction::Up, Direction::Left),
+        (-1, 1) => keep(Direction::Down, Direction::Left),
+        (1, 1) => keep(Direction::Down, Direction::Right),
+        _ => worker.direction,
+    };
+
+    let walking = moved != (0.0, 0.0);
+    let frames = if walking { 4 } else { 1 };
+    worker.anim_time += dt;
+    worker.sprite = Sprite::SpriteSheet {
+        texture: Texture::worker(color, walking, worker.direction),
+        frames,
+        current_frame: (worker.anim_time * ANIMATION_FPS) as u16 % frames,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUSE: BuildingKind = BuildingKind::House;
+
+    fn place(game: &mut Game, pos: TilePos) -> Result<(), ErrorCode> {
+        game.command(1, Command::PlaceBuilding { kind: HOUSE, pos })
+    }
+
+    /// Returns the first spot a house can be placed on
+    fn free_spot(game: &Game) -> TilePos {
+        let blocked = game.blocked_tiles();
+        let workers: FxHashSet<TilePos> = game
+            .players
+            .iter()
+            .flat_map(|player| player.workers.iter())
+            .flat_map(|worker| worker.tiles())
+            .collect();
+        let (width, height) = HOUSE.size();
+        (0..game.map.height as u32 - height)
+            .flat_map(|y| (0..game.map.width as u32 - width).map(move |x| (x, y)))
+            .find(|pos| {
+                ServerBuilding {
+                    kind: HOUSE,
+                    pos: *pos,
+                }
+                .tiles()
+                .all(|tile| walkable(tile, &game.map, &blocked) && !workers.contains(&tile))
+            })
+            .unwrap()
+    }
+
+    fn game() -> Game {
+        let mut game = Game::new();
+        game.add_player(1);
+        game
+    }
+
+    #[test]
+    fn placement_off_the_map() {
+        let mut game = game();
+        let (width, height) = (game.map.width as u32, game.map.height as u32);
+        for pos in [
+            (width - 3, 0),
+            (0, height - 3),
+            (width, height),
+            (u16::MAX as u32, u16::MAX as u32),
+            (u32::MAX, 0),
+            (0, u32::MAX),
+        ] {
+            assert!(matches!(
+                place(&mut game, pos),
+                Err(ErrorCode::BuildingBlocked)
+            ));
+        }
+        // Right up against the edge is still checked like anywhere else
+        let (house_width, house_height) = HOUSE.size();
+        assert!(place(&mut game, (width - house_width, height - house_height)).is_err());
+    }
+
+    #[test]
+    fn placement_costs_ore() {
+        let mut game = game();
+        let pos = free_spot(&game);
+        assert!(matches!(
+            place(&mut game, pos),
+            Err(ErrorCode::CannotAfford)
+        ));
+        assert!(game.players[0].buildings.is_empty());
+
+        game.players[0].ores.insert(OreKind::Gold, 10);
+        assert!(place(&mut game, pos).is_ok());
+        assert_eq!(game.players[0].ores[&OreKind::Gold], 0);
+        assert_eq!(game.players[0].buildings.len(), 1);
+        // The house now stands there
+        game.players[0].ores.insert(OreKind::Gold, 10);
+        assert!(matches!(
+            place(&mut game, pos),
+            Err(ErrorCode::BuildingBlocked)
+        ));
+    }
 }
@@ -0,0 +1,989 @@
+//! Every game runs in its own task that owns its state, so a slow game never holds up the socket or other games. Everything else talks to a game by sending it a [GameMessage]
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use ak_server::game::{Command, Game, GameMode, GameSettings, Turn, TurnEvent, TURN_TICKS};
+use ak_server::hashmap;
+use ak_server::types_client::ChatScope;
+use ak_server::types_server::{
+    Chat, Desync, ErrorCode, GameSummary, LobbyPlayer, LobbySpectator, LobbyState, LockstepStart,
+    ReconnectToken, ResponseData, ServerMessage, Snapshot,
+};
+use ak_server::util::now;
+use lazy_static::lazy_static;
+use rustc_hash::{FxHashMap, FxHashSet};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::config::config;
+use crate::profiles::record_game;
+use crate::session::with_session;
+use crate::transport::{send, send_unreliable, Responder};
+
+#[derive(Debug)]
+pub enum GameMessage {
+    /// A connection wants to join, the game answers the request. The session's game is set before this is sent
+    Join(Responder),
+    /// A connection wants to watch the game, the game answers the request. The session's game is set before this is sent
+    Spectate(Responder),
+    /// A player that dropped wants their slot back with the token, the game answers the request. The session's game is set before this is sent
+    Reconnect(Responder, String),
+    /// A player or spectator left the game on purpose
+    Leave(u64),
+    /// A player's or spectator's connection closed or timed out
+    Dropped(u64),
+    /// The host wants a player or spectator out of the game, the game answers the request
+    Kick(Responder, u64),
+    /// The host changed the settings before the game started, the game answers the request
+    Settings(Responder, GameSettings),
+    /// A player is or isn't ready for the game to start, the game answers the request
+    Ready(Responder, bool),
+    /// The host wants an AI player in the lobby, the game answers the request
+    AddAi(Responder),
+    /// The host wants to start the game, the game answers the request
+    Start(Responder),
+    /// A player sent a chat message, the game answers the request
+    Chat(Responder, ChatScope, String),
+    /// A player wants to do something in the game, the game answers the request
+    Command(Responder, Command),
+    /// A player has the snapshot from this tick, never answered
+    Ack(u64, u64),
+    /// A lockstep player's `(uuid, turn, checksum)`, never answered
+    Checksum(u64, u64, u64),
+    /// The server is stopping, the game ends and saves the stats of everyone still in it
+    Shutdown,
+}
+
+/// How often [end_games] checks whether every game has ended
+const END_GAMES_POLL: Duration = Duration::from_millis(20);
+
+/// How many past snapshots a game keeps to send deltas from, players that haven't acked one of them get a full snapshot
+const SNAPSHOT_HISTORY: usize = 32;
+
+/// The snapshots a game sent recently and the newest one each player has
+#[derive(Default)]
+struct SnapshotHistory {
+    snapshots: VecDeque<Snapshot>,
+    acks: FxHashMap<u64, u64>,
+}
+impl SnapshotHistory {
+    fn push(&mut self, snapshot: Snapshot) {
+        if self.snapshots.len() >= SNAPSHOT_HISTORY {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(snapshot);
+    }
+
+    /// Records an ack, acks arriving out of order never move a player back
+    fn ack(&mut self, uuid: u64, tick: u64) {
+        let acked = self.acks.entry(uuid).or_insert(tick);
+        *acked = (*acked).max(tick);
+    }
+
+    /// The snapshot a player's next delta should be from, `None` if they need a full one
+    fn baseline(&self, uuid: u64) -> Option<&Snapshot> {
+        let tick = *self.acks.get(&uuid)?;
+        self.snapshots.iter().find(|snapshot| snapshot.tick == tick)
+    }
+}
+
+/// How the rest of the server sees a running game
+struct GameHandle {
+    sender: UnboundedSender<GameMessage>,
+    /// Kept up to date by the game's task so the game list doesn't have to ask every game
+    summary: GameSummary,
+    /// Join code of a private game, private games aren't listed
+    code: Option<String>,
+    /// Last lobby state the game sent, for the admin console
+    lobby: Option<LobbyState>,
+}
+
+lazy_static! {
+    /// Map of every running game to its handle. Only used for routing, the games themselves are owned by their tasks
+    static ref GAMES: Mutex<FxHashMap<Uuid, GameHandle>> = Mutex::from(hashmap! {});
+}
+
+/// Characters join codes are made of, leaving out ones that are easy to mix up
+const CODE_CHARS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+const CODE_LEN: usize = 6;
+
+/// Most spectators a game can have, they don't take a [ak_server::types_game::Color]
+const MAX_SPECTATORS: usize = 16;
+
+/// Makes a join code no running game has
+fn new_code(games: &FxHashMap<Uuid, GameHandle>) -> String {
+    loop {
+        let code: String = (0..CODE_LEN)
+            .map(|_| CODE_CHARS[rand::random::<usize>() % CODE_CHARS.len()] as char)
+            .collect();
+        if !games
+            .values()
+            .any(|handle| handle.code.as_deref() == Some(code.as_str()))
+        {
+            return code;
+        }
+    }
+}
+
+/// Starts a task running the game with `spectators` already watching, returns `None` if the server already has [crate::config::ServerConfig::max_games] games. Private games get a join code, which is returned with the game's uuid. The `welcome` responses are sent before anything from the game itself
+pub fn spawn_game(
+    game: Game,
+    host: Option<u64>,
+    private: bool,
+    spectators: Vec<u64>,
+    welcome: Vec<(Responder, ResponseData)>,
+) -> Option<(Uuid, Option<String>)> {
+    let game_uuid = game.uuid;
+
+    let mut games = GAMES.lock().unwrap();
+    if games.len() >= config().max_games {
+        return None;
+    }
+
+    let code = private.then(|| new_code(&games));
+
+    let (sender, receiver) = unbounded_channel();
+    games.insert(
+        game_uuid,
+        GameHandle {
+            sender,
+            summary: game.summary(),
+            code: code.clone(),
+            lobby: None,
+        },
+    );
+    let total = games.len();
+    drop(games);
+
+    tokio::spawn(run_game(
+        game,
+        host,
+        code.clone(),
+        spectators,
+        welcome,
+        receiver,
+    ));
+    info!(game = %game_uuid, total, "New game");
+    Some((game_uuid, code))
+}
+
+/// Sends a message to a game, returns `false` if the game isn't running
+pub fn send_game(game_uuid: Uuid, message: GameMessage) -> bool {
+    match GAMES.lock().unwrap().get(&game_uuid) {
+        Some(handle) => handle.sender.send(message).is_ok(),
+        None => false,
+    }
+}
+
+/// Returns the summary of every public running game
+pub fn list_games() -> Vec<GameSummary> {
+    GAMES
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|handle| handle.code.is_none())
+        .map(|handle| handle.summary)
+        .collect()
+}
+
+/// Returns the private game with this join code, codes aren't case sensitive
+pub fn find_by_code(code: &str) -> Option<Uuid> {
+    let code = code.to_uppercase();
+    GAMES
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(_, handle)| handle.code.as_deref() == Some(code.as_str()))
+        .map(|(uuid, _)| *uuid)
+}
+
+/// Returns the summary and last lobby state of every running game, private ones included
+pub fn game_details() -> Vec<(GameSummary, Option<LobbyState>)> {
+    GAMES
+        .lock()
+        .unwrap()
+        .values()
+        .map(|handle| (handle.summary, handle.lobby.clone()))
+        .collect()
+}
+
+/// Returns how many games are running, lobbies and private ones included
+pub fn game_count() -> usize {
+    GAMES.lock().unwrap().len()
+}
+
+/// Ends every game so they save their players' stats, waiting up to `timeout` for them to finish. Returns how many games were still running when it ran out
+pub async fn end_games(timeout: Duration) -> usize {
+    let senders: Vec<UnboundedSender<GameMessage>> = GAMES
+        .lock()
+        .unwrap()
+        .values()
+        .map(|handle| handle.sender.clone())
+        .collect();
+    for sender in senders {
+        let _ = sender.send(GameMessage::Shutdown);
+    }
+
+    // Games remove themselves once their stats are saved
+    let deadline = Instant::now() + timeout;
+    while game_count() > 0 && Instant::now() < deadline {
+        tokio::time::sleep(END_GAMES_POLL).await;
+    }
+    game_count()
+}
+
+pub fn is_private(game_uuid: Uuid) -> bool {
+    GAMES
+        .lock()
+        .unwrap()
+        .get(&game_uuid)
+        .is_some_and(|handle| handle.code.is_some())
+}
+
+/// Clears a connection's game if it is still set to `game_uuid`, for joins that didn't go through
+fn cancel_join(uuid: u64, game_uuid: Uuid) {
+    with_session(uuid, |session| {
+        if session.game == Some(game_uuid) {
+            session.game = None;
+        }
+    });
+}
+
+/// How many past turns' checksums a lockstep game keeps, checksums for older turns are ignored
+const CHECKSUM_HISTORY: usize = 64;
+
+/// What a lockstep game needs between turns
+#[derive(Default)]
+struct TurnHistory {
+    /// Events since the last turn, in the order they were applied
+    events: Vec<TurnEvent>,
+    /// The game as it was when the last turn ended, before any of [Self::events] were applied
+    start: Option<Game>,
+    /// The server's `(turn, checksum)` for recent turns
+    checksums: VecDeque<(u64, u64)>,
+    /// Players that desynced, only reported once
+    desynced: FxHashSet<u64>,
+}
+impl TurnHistory {
+    fn push_checksum(&mut self, turn: u64, checksum: u64) {
+        if self.checksums.len() >= CHECKSUM_HISTORY {
+            self.checksums.pop_front();
+        }
+        self.checksums.push_back((turn, checksum));
+    }
+}
+
+/// A game along with everything its task keeps next to it
+struct GameTask {
+    game: Game,
+    history: SnapshotHistory,
+    turns: TurnHistory,
+    /// Player or spectator that can kick, change settings and start the game, `None` for games from matchmaking
+    host: Option<u64>,
+    /// Connections watching the game, in the order they started watching
+    spectators: Vec<u64>,
+    /// Players that sent the ready-check, only used before the game starts
+    ready: FxHashSet<u64>,
+    /// Newest reconnect token of every player
+    tokens: FxHashMap<u64, String>,
+    /// Players whose connection dropped and when, their slot is kept for [crate::config::ServerConfig::reconnect_grace]
+    dropped: FxHashMap<u64, u64>,
+    /// Join code of a private game
+    code: Option<String>,
+    /// Map of players logged into a profile when the game started to the profile, stats are saved when they leave
+    profiles: FxHashMap<u64, Uuid>,
+    /// Whether more than one person was playing when the game started, games against only AI players can't be won
+    contested: bool,
+    /// The person left playing after every other person quit
+    winner: Option<u64>,
+    /// Stats still being written to profiles, the task waits for them before it ends
+    saving: Vec<JoinHandle<()>>,
+    /// Length of a tick in seconds
+    dt: f32,
+}
+impl GameTask {
+    fn lockstep(&self) -> bool {
+        self.game.mode == GameMode::Lockstep
+    }
+
+    /// The game lockstep clients joining mid-turn start from, they apply the turn's events on top once it arrives. `None` for games that aren't lockstep
+    fn lockstep_start(&self) -> Option<Game> {
+        if !self.lockstep() {
+            return None;
+        }
+        self.turns.start.clone()
+    }
+
+    /// Remembers the game as it is now as the start of the current turn, called once the game starts and after every turn
+    fn mark_turn(&mut self) {
+        if self.lockstep() {
+            self.turns.start = Some(self.game.clone());
+        }
+    }
+
+    /// Uuids of every player and spectator
+    fn members(&self) -> Vec<u64> {
+        self.game
+            .players
+            .iter()
+            .map(|player| player.uuid)
+            .chain(self.spectators.iter().copied())
+            .collect()
+    }
+
+    fn is_member(&self, uuid: u64) -> bool {
+        self.game.has_player(uuid) || self.spectators.contains(&uuid)
+    }
+
+    /// Whether anyone other than AI players is playing or watching
+    fn has_people(&self) -> bool {
+        self.game.has_people() || !self.spectators.is_empty()
+    }
+
+    /// Sends a message reliably to every player and spectator in the game
+    fn broadcast(&self, message: &ServerMessage) {
+        let payload = rmp_serde::to_vec(message).unwrap();
+        for uuid in self.members() {
+            send(uuid, payload.clone());
+        }
+    }
+
+    /// Sends who is in the game and who the host is to every player and spectator, and keeps it on the handle for the admin console
+    fn broadcast_lobby(&self) {
+        let players = self
+            .game
+            .players
+            .iter()
+            .map(|player| LobbyPlayer {
+                uuid: player.uuid,
+                username: with_session(player.uuid, |session| session.username.clone())
+                    .unwrap_or_default(),
+                color: player.color,
+                ready: self.ready.contains(&player.uuid),
+                ai: player.ai,
+                connected: !self.dropped.contains_key(&player.uuid),
+            })
+            .collect();
+        let spectators = self
+            .spectators
+            .iter()
+            .map(|uuid| LobbySpectator {
+                uuid: *uuid,
+                username: with_session(*uuid, |session| session.username.clone())
+                    .unwrap_or_default(),
+            })
+            .collect();
+        let lobby = LobbyState {
+            host: self.host,
+            code: self.code.clone(),
+            started: self.game.started,
+            settings: self.game.settings,
+            players,
+            spectators,
+        };
+        if let Some(handle) = GAMES.lock().unwrap().get_mut(&self.game.uuid) {
+            handle.lobby = Some(lobby.clone());
+        }
+        self.broadcast(&ServerMessage::Lobby(lobby));
+    }
+
+    /// Sends what a player or spectator needs to follow the game once it has started, lockstep members get the game from the start of the turn
+    fn send_start(&self, uuid: u64) {
+        let message = match self.lockstep_start() {
+            Some(game) => ServerMessage::LockstepStart(LockstepStart { game, dt: self.dt }),
+            None => ServerMessage::Map(self.game.map.clone()),
+        };
+        send(uuid, rmp_serde::to_vec(&message).unwrap());
+    }
+
+    /// Gives a player a new reconnect token, replacing their old one
+    fn issue_token(&mut self, uuid: u64) {
+        let token = format!("{:032x}", rand::random::<u128>());
+        let message = ServerMessage::ReconnectToken(ReconnectToken {
+            game: self.game.uuid,
+            token: token.clone(),
+        });
+        send(uuid, rmp_serde::to_vec(&message).unwrap());
+        self.tokens.insert(uuid, token);
+    }
+
+    /// Remembers which players are logged into a profile, called once the game starts
+    fn load_profiles(&mut self) {
+        let people: Vec<u64> = self
+            .game
+            .players
+            .iter()
+            .filter(|player| !player.ai)
+            .map(|player| player.uuid)
+            .collect();
+        self.contested = people.len() > 1;
+        for uuid in people {
+            if let Some(Some(profile)) = with_session(uuid, |session| session.profile) {
+                self.profiles.insert(uuid, profile);
+            }
+        }
+    }
+
+    /// Saves the game to the profile of a player leaving it
+    fn save_stats(&mut self, uuid: u64) {
+        let Some(profile) = self.profiles.remove(&uuid) else {
+            return;
+        };
+        let gathered = self
+            .game
+            .players
+            .iter()
+            .find(|player| player.uuid == uuid)
+            .map_or(0, |player| player.gathered);
+        self.saving.retain(|handle| !handle.is_finished());
+        self.saving
+            .push(record_game(profile, self.winner == Some(uuid), gathered));
+    }
+
+    /// Takes a player or spectator out of the game, passing host to the next person if they were host. Returns `false` once only AI players are left
+    fn remove_player(&mut self, uuid: u64) -> bool {
+        if self.game.has_player(uuid) {
+            if self.game.started {
+                self.save_stats(uuid);
+            }
+            if self.lockstep() && self.game.started {
+                self.turns.events.push(TurnEvent::Leave(uuid));
+            }
+            self.game.remove_player(uuid);
+
+            // The last person playing wins once everyone else has quit
+            let mut people = self.game.players.iter().filter(|player| !player.ai);
+            if let (Some(last), None) = (people.next(), people.next()) {
+                if self.game.started && self.contested && self.winner.is_none() {
+                    self.winner = Some(last.uuid);
+                }
+            }
+        } else if self.spectators.contains(&uuid) {
+            self.spectators.retain(|spectator| *spectator != uuid);
+        } else {
+            return true;
+        }
+        self.history.acks.remove(&uuid);
+        self.turns.desynced.remove(&uuid);
+        self.ready.remove(&uuid);
+        self.tokens.remove(&uuid);
+        self.dropped.remove(&uuid);
+
+        // AI players don't keep a game going on their own, but someone watching them does
+        if !self.has_people() {
+            return false;
+        }
+
+        if self.host == Some(uuid) {
+            self.host = self
+                .game
+                .players
+                .iter()
+                .find(|player| !player.ai)
+                .map(|player| player.uuid)
+                .or(self.spectators.first().copied());
+            info!(game = %self.game.uuid, host = ?self.host, "Host moved");
+        }
+        self.broadcast_lobby();
+        true
+    }
+
+    /// Checks that a request from `uuid` can change the lobby, `host_only` for what only the host can do
+    fn check_lobby(&self, uuid: u64, host_only: bool) -> Result<(), ErrorCode> {
+        if !self.is_member(uuid) {
+            return Err(ErrorCode::NotInGame);
+        }
+        if host_only && self.host != Some(uuid) {
+            return Err(ErrorCode::NotHost);
+        }
+        if self.game.started {
+            return Err(ErrorCode::AlreadyStarted);
+        }
+        Ok(())
+    }
+
+    /// Takes the game out of its lobby once everyone is ready
+    fn start(&mut self, uuid: u64) -> Result<(), ErrorCode> {
+        self.check_lobby(uuid, true)?;
+        let everyone_ready = self
+            .game
+            .players
+            .iter()
+            .all(|player| player.ai || self.ready.contains(&player.uuid));
+        if self.game.players.is_empty() || !everyone_ready {
+            return Err(ErrorCode::NotReady);
+        }
+
+        self.game.started = true;
+        self.ready.clear();
+        self.load_profiles();
+        self.mark_turn();
+        for uuid in self.members() {
+            self.send_start(uuid);
+        }
+        self.broadcast_lobby();
+        info!(game = %self.game.uuid, "Game started");
+        Ok(())
+    }
+
+    /// Checks that a connection joining or spectating is still set to this game, it might have closed or left while the request was on its way
+    fn still_joining(&self, responder: Responder) -> bool {
+        match with_session(responder.uuid, |session| {
+            session.game == Some(self.game.uuid)
+        }) {
+            Some(true) => true,
+            Some(false) => {
+                responder.respond(ResponseData::Error(ErrorCode::NotInGame));
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Handles a message sent to the game, returns `false` once the game should end
+    fn handle_message(&mut self, message: GameMessage) -> bool {
+        match message {
+            GameMessage::Join(responder) => {
+                let uuid = responder.uuid;
+                if !self.still_joining(responder) {
+                    return true;
+                }
+
+                // Lockstep players start from the start of the turn, and add themselves with the join event along with the turn's other events
+                let joining_lockstep = self.lockstep() && self.game.started;
+                match self.game.add_player(uuid) {
+                    Some(color) => {
+                        responder.respond(ResponseData::GameJoinSuccess(color));
+                        if self.game.started {
+                            self.send_start(uuid);
+                        }
+                        if joining_lockstep {
+                            self.turns.events.push(TurnEvent::Join(uuid));
+                        }
+                        self.issue_token(uuid);
+                        self.broadcast_lobby();
+                    }
+                    None => {
+                        cancel_join(uuid, self.game.uuid);
+                        responder.respond(ResponseData::Error(ErrorCode::GameFull));
+                    }
+                }
+            }
+            GameMessage::Spectate(responder) => {
+                let uuid = responder.uuid;
+                if !self.still_joining(responder) {
+                    return true;
+                }
+                if self.spectators.len() >= MAX_SPECTATORS {
+                    cancel_join(uuid, self.game.uuid);
+                    responder.respond(ResponseData::Error(ErrorCode::SpectatorsFull));
+                    return true;
+                }
+
+                // Lockstep spectators apply turns like players do, so they start from the start of the turn too
+                self.spectators.push(uuid);
+                responder.respond(ResponseData::Success);
+                if self.game.started {
+                    self.send_start(uuid);
+                }
+                self.broadcast_lobby();
+                return true;
+            }
+            GameMessage::Reconnect(responder, token) => {
+                let uuid = responder.uuid;
+                if !self.still_joining(responder) {
+                    return true;
+                }
+                let Some(old) = self
+                    .tokens
+                    .iter()
+                    .find(|(_, issued)| **issued == token)
+                    .map(|(old, _)| *old)
+                else {
+                    cancel_join(uuid, self.game.uuid);
+                    responder.respond(ResponseData::Error(ErrorCode::InvalidToken));
+                    return true;
+                };
+
+                // The old connection might not have timed out yet, it is out of the game either way. Sessions keep their id across reconnects, in which case there is nothing to hand over
+                if old != uuid {
+                    cancel_join(old, self.game.uuid);
+                }
+                self.tokens.remove(&old);
+                self.dropped.remove(&old);
+                self.history.acks.remove(&old);
+                self.turns.desynced.remove(&old);
+                if self.ready.remove(&old) {
+                    self.ready.insert(uuid);
+                }
+                if self.host == Some(old) {
+                    self.host = Some(uuid);
+                }
+                if self.winner == Some(old) {
+                    self.winner = Some(uuid);
+                }
+                if let Some(profile) = self.profiles.remove(&old) {
+                    self.profiles.insert(uuid, profile);
+                }
+
+                // Like joining, lockstep players start from the start of the turn, and take the slot over with the reconnect event
+                let color = self.game.rekey_player(old, uuid).unwrap();
+                if self.lockstep() && self.game.started && old != uuid {
+                    self.turns.events.push(TurnEvent::Reconnect(old, uuid));
+                }
+
+                responder.respond(ResponseData::GameJoinSuccess(color));
+                if self.game.started {
+                    self.send_start(uuid);
+                }
+                self.issue_token(uuid);
+                self.broadcast_lobby();
+                info!(game = %self.game.uuid, old, connection = uuid, "Player reconnected");
+            }
+            GameMessage::Leave(uuid) => {
+                if !self.remove_player(uuid) {
+                    return false;
+                }
+            }
+            GameMessage::Dropped(uuid) => {
+                // Players of started games keep their slot so they can reconnect, anyone else just leaves
+                if self.game.started && self.tokens.contains_key(&uuid) {
+                    self.dropped.insert(uuid, now());
+                    self.broadcast_lobby();
+                    warn!(game = %self.game.uuid, connection = uuid, "Player dropped");
+                } else if !self.remove_player(uuid) {
+                    return false;
+                }
+            }
+            GameMessage::Kick(responder, target) => {
+                if self.host != Some(responder.uuid) {
+                    responder.respond(ResponseData::Error(ErrorCode::NotHost));
+                    return true;
+                }
+                if target == responder.uuid || !self.is_member(target) {
+                    responder.respond(ResponseData::Error(ErrorCode::PlayerNotFound));
+                    return true;
+                }
+
+                cancel_join(target, self.game.uuid);
+                send(target, rmp_serde::to_vec(&ServerMessage::Kicked).unwrap());
+                responder.respond(ResponseData::Success);
+                if !self.remove_player(target) {
+                    return false;
+                }
+            }
+            GameMessage::Settings(responder, settings) => {
+                let result = self.check_lobby(responder.uuid, true).and_then(|()| {
+                    if !settings.valid() {
+                        return Err(ErrorCode::InvalidSettings);
+                    }
+                    // Ready-checks were for the old settings
+                    self.game.set_settings(settings);
+                    self.ready.clear();
+                    Ok(())
+                });
+                if result.is_ok() {
+                    self.broadcast_lobby();
+                }
+                respond_result(responder, result);
+            }
+            GameMessage::Ready(responder, ready) => {
+                let result = self.check_lobby(responder.uuid, false).and_then(|()| {
+                    // Only players are waited on
+                    if !self.game.has_player(responder.uuid) {
+                        return Err(ErrorCode::Spectating);
+                    }
+                    if ready {
+                        self.ready.insert(responder.uuid);
+                    } else {
+                        self.ready.remove(&responder.uuid);
+                    }
+                    Ok(())
+                });
+                if result.is_ok() {
+                    self.broadcast_lobby();
+                }
+                respond_result(responder, result);
+                return true;
+            }
+            GameMessage::AddAi(responder) => {
+                let result = self
+                    .check_lobby(responder.uuid, true)
+                    .and_then(|()| self.game.add_ai().map(|_| ()).ok_or(ErrorCode::GameFull));
+                if result.is_ok() {
+                    self.broadcast_lobby();
+                }
+                respond_result(responder, result);
+            }
+            GameMessage::Start(responder) => {
+                let result = self.start(responder.uuid);
+                respond_result(responder, result);
+            }
+            GameMessage::Chat(responder, scope, text) => {
+                let uuid = responder.uuid;
+                if !self.is_member(uuid) {
+                    responder.respond(ResponseData::Error(ErrorCode::NotInGame));
+                    return true;
+                }
+                // Spectators are a team of their own
+                let team_of = |uuid: u64| {
+                    self.game
+                        .players
+                        .iter()
+                        .find(|player| player.uuid == uuid)
+                        .map(|player| player.team)
+                };
+                let team = team_of(uuid);
+
+                let chat = Chat {
+                    from: uuid,
+                    username: with_session(uuid, |session| session.username.clone())
+                        .unwrap_or_default(),
+                    scope,
+                    text,
+                };
+                let payload = rmp_serde::to_vec(&ServerMessage::Chat(chat)).unwrap();
+                for member in self.members() {
+                    if scope == ChatScope::All || team_of(member) == team {
+                        send(member, payload.clone());
+                    }
+                }
+                responder.respond(ResponseData::Success);
+                return true;
+            }
+            GameMessage::Command(responder, command) => {
+                if !self.game.started {
+                    responder.respond(ResponseData::Error(ErrorCode::NotStarted));
+                    return true;
+                }
+                if self.spectators.contains(&responder.uuid) {
+                    responder.respond(ResponseData::Error(ErrorCode::Spectating));
+                    return true;
+                }
+
+                let result = self.game.command(responder.uuid, command);
+                if result.is_ok() && self.lockstep() {
+                    self.turns
+                        .events
+                        .push(TurnEvent::Command(responder.uuid, command));
+                }
+                respond_result(responder, result);
+                return true;
+            }
+            GameMessage::Ack(uuid, tick) => {
+                // Acks for ticks that haven't happened yet are made up
+                if self.is_member(uuid) && tick <= self.game.tick {
+                    self.history.ack(uuid, tick);
+                }
+                return true;
+            }
+            GameMessage::Checksum(uuid, turn, checksum) => {
+                let expected = self
+                    .turns
+                    .checksums
+                    .iter()
+                    .find(|(checked, _)| *checked == turn)
+                    .map(|(_, expected)| *expected);
+                if expected.is_some_and(|expected| expected != checksum)
+                    && self.is_member(uuid)
+                    && self.turns.desynced.insert(uuid)
+                {
+                    warn!(game = %self.game.uuid, turn, connection = uuid, "Desync");
+                    self.broadcast(&ServerMessage::Desync(Desync { turn, player: uuid }));
+                }
+                return true;
+            }
+            GameMessage::Shutdown => return false,
+        }
+
+        if let Some(handle) = GAMES.lock().unwrap().get_mut(&self.game.uuid) {
+            handle.summary = self.game.summary();
+        }
+        true
+    }
+
+    /// Sends the game's state to every player and spectator, as a delta from the last snapshot they acked. Every player is in the snapshot, nothing is hidden from anyone
+    fn broadcast_snapshot(&mut self) {
+        let history = &mut self.history;
+        history.push(self.game.snapshot());
+        let snapshot = history.snapshots.back().unwrap();
+
+        // Players acking the same tick get the same delta, so each one is only encoded once
+        let mut payloads: FxHashMap<Option<u64>, Vec<u8>> = hashmap! {};
+        for uuid in self
+            .game
+            .players
+            .iter()
+            .map(|player| player.uuid)
+            .chain(self.spectators.iter().copied())
+        {
+            let baseline = history.baseline(uuid);
+            let payload = payloads
+                .entry(baseline.map(|baseline| baseline.tick))
+                .or_insert_with(|| {
+                    rmp_serde::to_vec(&ServerMessage::Snapshot(snapshot.delta(baseline))).unwrap()
+                });
+            send_unreliable(uuid, payload.clone());
+        }
+    }
+
+    /// Simulates a lockstep turn and sends its events to every player. The events were already applied as they came in, in the same order clients apply them
+    fn run_turn(&mut self) {
+        self.game.step_turn(self.dt);
+        let turn = Turn {
+            turn: self.game.turn(),
+            events: std::mem::take(&mut self.turns.events),
+        };
+        self.turns.push_checksum(turn.turn, self.game.checksum());
+        self.mark_turn();
+        self.broadcast(&ServerMessage::Turn(turn));
+    }
+
+    /// Takes out every dropped player whose grace period ran out, returns `false` once only AI players are left
+    fn expire_dropped(&mut self) -> bool {
+        let cutoff = now().saturating_sub(config().reconnect_grace * 1000);
+        let expired: Vec<u64> = self
+            .dropped
+            .iter()
+            .filter(|(_, since)| **since < cutoff)
+            .map(|(uuid, _)| *uuid)
+            .collect();
+        expired.into_iter().all(|uuid| self.remove_player(uuid))
+    }
+
+    /// Advances the game by a tick, or a turn for lockstep games. Games in their lobby aren't simulated. Returns `false` once the game should end
+    fn tick(&mut self) -> bool {
+        if !self.expire_dropped() {
+            return false;
+        }
+        if !self.game.started {
+            return true;
+        }
+
+        // Every tick is the same length no matter how late it runs, so the simulation doesn't depend on timing
+        if self.lockstep() {
+            self.run_turn();
+        } else {
+            self.game.update(self.dt);
+            self.broadcast_snapshot();
+        }
+        true
+    }
+}
+
+/// Answers a request with [ResponseData::Success] or the error
+fn respond_result(responder: Responder, result: Result<(), ErrorCode>) {
+    let data = match result {
+        Ok(()) => ResponseData::Success,
+        Err(err) => ResponseData::Error(err),
+    };
+    responder.respond(data);
+}
+
+/// Runs a game, ticking it at [crate::config::ServerConfig::tick_rate] and handling messages in between, until every person has left. Lockstep games are ticked a turn at a time
+async fn run_game(
+    game: Game,
+    host: Option<u64>,
+    code: Option<String>,
+    spectators: Vec<u64>,
+    welcome: Vec<(Responder, ResponseData)>,
+    mut receiver: UnboundedReceiver<GameMessage>,
+) {
+    for (responder, data) in welcome {
+        responder.respond(data);
+    }
+
+    let tick_rate = config().tick_rate;
+    let period = if game.mode == GameMode::Lockstep {
+        Duration::from_secs(TURN_TICKS) / tick_rate
+    } else {
+        Duration::from_secs(1) / tick_rate
+    };
+    let mut interval = tokio::time::interval(period);
+
+    let mut task = GameTask {
+        game,
+        history: SnapshotHistory::default(),
+        turns: TurnHistory::default(),
+        host,
+        spectators,
+        ready: FxHashSet::default(),
+        tokens: hashmap! {},
+        dropped: hashmap! {},
+        code,
+        profiles: hashmap! {},
+        contested: false,
+        winner: None,
+        saving: vec![],
+        dt: 1.0 / tick_rate as f32,
+    };
+
+    // Whoever created the game is already in it
+    let people: Vec<u64> = task
+        .game
+        .players
+        .iter()
+        .filter(|player| !player.ai)
+        .map(|player| player.uuid)
+        .collect();
+    for uuid in people {
+        task.issue_token(uuid);
+    }
+    task.broadcast_lobby();
+    if task.game.started {
+        task.load_profiles();
+        task.mark_turn();
+        for uuid in task.members() {
+            task.send_start(uuid);
+        }
+    }
+
+    loop {
+        tokio::select! {
+            message = receiver.recv() => {
+                let Some(message) = message else {
+                    break;
+                };
+                if !task.handle_message(message) {
+                    break;
+                }
+            }
+            _ = interval.tick() => {
+                if !task.tick() {
+                    break;
+                }
+            }
+        }
+    }
+
+    // Players still in the game when it ends finished it too
+    let players: Vec<u64> = task.profiles.keys().copied().collect();
+    for uuid in players {
+        task.save_stats(uuid);
+    }
+    for handle in task.saving.drain(..) {
+        let _ = handle.await;
+    }
+
+    let game_uuid = task.game.uuid;
+    GAMES.lock().unwrap().remove(&game_uuid);
+
+    // Joins sent before the game was removed never made it in
+    receiver.close();
+    while let Ok(message) = receiver.try_recv() {
+        if let GameMessage::Join(responder)
+        | GameMessage::Spectate(responder)
+        | GameMessage::Reconnect(responder, _) = message
+        {
+            cancel_join(responder.uuid, game_uuid);
+            responder.respond(ResponseData::Error(ErrorCode::GameNotFound));
+        }
+    }
+
+    info!(game = %game_uuid, "Game ended");
+}
@@ -1,78 +1,383 @@
 #[allow(clippy::module_inception)]
 #[cfg(feature = "server")]
 pub mod handle_request {
-    use std::sync::Mutex;
-
-    use ak_server::game::{in_game, Game, CONN_GAMES, GAMES};
-    use ak_server::hashmap;
-    use ak_server::types_client::ClientRequest;
-    use ak_server::types_game::{Color, ServerPlayer};
+    use ak_server::game::{Command, Game};
+    use ak_server::types_client::{ClientRequest, MAX_CHAT_LENGTH};
     use ak_server::types_server::{ErrorCode, ResponseData};
-    use lazy_static::lazy_static;
-    use rustc_hash::FxHashMap;
+    use tokio::task::spawn_blocking;
+    use uuid::Uuid;
 
-    use crate::add_username;
+    use crate::bans::{strike, Strike};
+    use crate::games::{find_by_code, is_private, list_games, send_game, spawn_game, GameMessage};
+    use crate::matchmaking::{is_queued, leave_queue, queue};
+    use crate::profiles::{create_profile, login, name_taken, profile, rename_profile};
+    use crate::ratelimit::{allow, allow_source, Source};
+    use crate::session::{close_connection, connect, issue_token, touch, with_session};
+    use crate::transport::{ip, unroute, Responder};
 
-    lazy_static! {
-        static ref CONN_RATELIMIT: Mutex<FxHashMap<u64, u64>> = Mutex::from(hashmap! {});
+    /// Whether every character can be shown, for usernames and chat messages
+    fn valid_chars(input: &str) -> bool {
+        let valid_chars = " abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ1234567890!@#$%^&*()_+-=[]{};':\",./<>?\\|`~";
+        input.chars().all(|c| valid_chars.contains(c))
     }
 
-    /// The minimum amount of time between requests that are ratelimited
-
-    const RATELIMIT: u64 = 250;
-
     fn valid_username(input: &str) -> Option<ErrorCode> {
-        if input.len() <= 50 {
+        if input.len() > 50 {
             return Some(ErrorCode::UsernameTooLong);
         }
 
-        let valid_chars = " abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ1234567890!@#$%^&*()_+-=[]{};':\",./<>?\\|`~";
-        if input.chars().all(|c| valid_chars.contains(c)) {
+        if !valid_chars(input) {
             return Some(ErrorCode::UsernameInvalid);
         }
 
         None
     }
 
-    pub fn handle_request(uuid: u64, request: &ClientRequest) -> ResponseData {
-        // Check if request is ratelimited
-        if request.ratelimited() {
-            let mut ratelimits = CONN_RATELIMIT.lock().unwrap();
-            if let Some(last_req) = ratelimits.get(&uuid) {
-                if *last_req + RATELIMIT > request.timestamp() {
-                    return ResponseData::Error(ErrorCode::Ratelimited);
-                }
+    fn valid_message(input: &str) -> Option<ErrorCode> {
+        if input.len() > MAX_CHAT_LENGTH {
+            return Some(ErrorCode::MessageTooLong);
+        }
+
+        if input.trim().is_empty() || !valid_chars(input) {
+            return Some(ErrorCode::MessageInvalid);
+        }
+
+        None
+    }
+
+    /// Hands a request to the connection's game, returns the error to respond with if it couldn't be
+    fn forward(responder: Responder, message: GameMessage) -> Option<ResponseData> {
+        let game_uuid = match with_session(responder.uuid, |session| session.game).flatten() {
+            Some(game_uuid) => game_uuid,
+            None => return Some(ResponseData::Error(ErrorCode::NotInGame)),
+        };
+        if !send_game(game_uuid, message) {
+            return Some(ResponseData::Error(ErrorCode::GameNotFound));
+        }
+        None
+    }
+
+    fn send_command(responder: Responder, command: Command) -> Option<ResponseData> {
+        forward(responder, GameMessage::Command(responder, command))
+    }
+
+    /// Asks a game to let the connection in, as a player, spectator or reconnecting player depending on `message`. The game answers the request
+    fn join_game(
+        responder: Responder,
+        game_uuid: Uuid,
+        message: GameMessage,
+    ) -> Option<ResponseData> {
+        let uuid = responder.uuid;
+        if is_queued(uuid) {
+            return Some(ResponseData::Error(ErrorCode::AlreadyQueued));
+        }
+
+        // Set the game first so the connection can't join a second game while this one is answering
+        let joining = with_session(uuid, |session| {
+            if session.game.is_some() {
+                return false;
             }
-            ratelimits.insert(uuid, request.timestamp());
+            session.game = Some(game_uuid);
+            true
+        });
+        if joining != Some(true) {
+            return Some(ResponseData::Error(ErrorCode::AlreadyInGame));
         }
 
-        match request {
+        if !send_game(game_uuid, message) {
+            with_session(uuid, |session| session.game = None);
+            return Some(ResponseData::Error(ErrorCode::GameNotFound));
+        }
+        None
+    }
+
+    /// Counts a strike against the connection's address for hitting a ratelimit and returns the error to respond with
+    fn ratelimited(uuid: u64) -> Option<ResponseData> {
+        if let Some(ip) = ip(uuid) {
+            strike(ip, Strike::Ratelimited);
+        }
+        Some(ResponseData::Error(ErrorCode::Ratelimited))
+    }
+
+    /// Runs profile database work on a blocking thread so it doesn't hold up other requests, the request is answered once it is done
+    fn with_profiles(
+        responder: Responder,
+        work: impl FnOnce() -> ResponseData + Send + 'static,
+    ) -> Option<ResponseData> {
+        spawn_blocking(move || responder.respond(work()));
+        None
+    }
+
+    /// Handles a request, returning its response. Returns `None` if the request was handed to a game or a blocking thread, which answers it through the [Responder] instead
+    pub fn handle_request(request: &ClientRequest, responder: Responder) -> Option<ResponseData> {
+        let uuid = responder.uuid;
+
+        // Every request other than `Connect` needs a session, this also keeps the session alive
+        if !matches!(request, ClientRequest::Connect(_)) && !touch(uuid) {
+            return Some(ResponseData::Error(ErrorCode::NotConnected));
+        }
+
+        // Check if request is ratelimited
+        if !allow(uuid, request) {
+            return ratelimited(uuid);
+        }
+
+        let data = match request {
+            ClientRequest::Connect(_) => {
+                if !connect(uuid) {
+                    // Answered before unrouting, as without a session nothing keeps the route around
+                    responder.respond(ResponseData::Error(ErrorCode::ServerFull));
+                    unroute(uuid);
+                    return None;
+                }
+                ResponseData::Connected(issue_token(uuid))
+            }
+            ClientRequest::Disconnect(_) => {
+                // Disconnecting on purpose gives up the slot, only dropped connections can reconnect
+                if let Some(game_uuid) = with_session(uuid, |session| session.game.take()).flatten()
+                {
+                    send_game(game_uuid, GameMessage::Leave(uuid));
+                }
+                close_connection(uuid);
+                ResponseData::Success
+            }
             ClientRequest::Ping(_) => ResponseData::Success,
             ClientRequest::Rename(rename) => {
                 // Make sure name is valid
                 if let Some(err) = valid_username(&rename.name) {
-                    return ResponseData::Error(err);
+                    return Some(ResponseData::Error(err));
                 }
 
-                add_username(uuid, &rename.name);
-                ResponseData::Success
+                // Profiles keep their name unique, guests can't take the name of one
+                let profile = with_session(uuid, |session| session.profile).flatten();
+                let name = rename.name.clone();
+                return with_profiles(responder, move || {
+                    let result = match profile {
+                        Some(profile) => rename_profile(profile, &name),
+                        None => match name_taken(&name) {
+                            Ok(false) => Ok(()),
+                            Ok(true) => Err(ErrorCode::NameTaken),
+                            Err(err) => Err(err),
+                        },
+                    };
+                    if let Err(err) = result {
+                        return ResponseData::Error(err);
+                    }
+
+                    with_session(uuid, |session| session.username = name);
+                    ResponseData::Success
+                });
             }
-            ClientRequest::CreateGame(_) => {
-                if in_game(uuid) {
-                    return ResponseData::Error(ErrorCode::AlreadyInGame);
+            ClientRequest::CreateProfile(create) => {
+                if let Some(err) = valid_username(&create.name) {
+                    return Some(ResponseData::Error(err));
+                }
+                if with_session(uuid, |session| session.profile.is_some()) == Some(true) {
+                    return Some(ResponseData::Error(ErrorCode::AlreadyLoggedIn));
                 }
 
-                let game = Game::new(vec![ServerPlayer::new(uuid, Color::Blue)]);
-                let game_uuid = game.uuid;
+                // Sessions are cheap to get more of, so profiles are also limited per address
+                if let Some(ip) = ip(uuid) {
+                    if !allow_source(Source::Address(ip), "CreateProfile") {
+                        return ratelimited(uuid);
+                    }
+                }
 
-                let mut games = GAMES.lock().unwrap();
-                games.insert(game_uuid, game);
+                let name = create.name.clone();
+                return with_profiles(responder, move || match create_profile(&name) {
+                    Ok((profile, secret)) => {
+                        with_session(uuid, |session| {
+                            session.profile = Some(profile.id);
+                            session.username = profile.name.clone();
+                        });
+                        ResponseData::ProfileCreated { profile, secret }
+                    }
+                    Err(err) => ResponseData::Error(err),
+                });
+            }
+            ClientRequest::Login(login_request) => {
+                if with_session(uuid, |session| session.profile.is_some()) == Some(true) {
+                    return Some(ResponseData::Error(ErrorCode::AlreadyLoggedIn));
+                }
+
+                let (id, secret) = (login_request.id, login_request.secret.clone());
+                return with_profiles(responder, move || match login(id, &secret) {
+                    Ok(profile) => {
+                        with_session(uuid, |session| {
+                            session.profile = Some(profile.id);
+                            session.username = profile.name.clone();
+                        });
+                        ResponseData::Profile(profile)
+                    }
+                    Err(err) => ResponseData::Error(err),
+                });
+            }
+            ClientRequest::GetProfile(_) => {
+                let Some(id) = with_session(uuid, |session| session.profile).flatten() else {
+                    return Some(ResponseData::Error(ErrorCode::NotLoggedIn));
+                };
+                return with_profiles(responder, move || match profile(id) {
+                    Ok(profile) => ResponseData::Profile(profile),
+                    Err(err) => ResponseData::Error(err),
+                });
+            }
+            ClientRequest::CreateGame(create) => {
+                if with_session(uuid, |session| session.game.is_some()) == Some(true) {
+                    return Some(ResponseData::Error(ErrorCode::AlreadyInGame));
+                }
+                if is_queued(uuid) {
+                    return Some(ResponseData::Error(ErrorCode::AlreadyQueued));
+                }
 
-                let mut conn_games = CONN_GAMES.lock().unwrap();
-                conn_games.insert(uuid, game_uuid);
+                // Created games wait in their lobby until the host starts them
+                let mut game = Game::new();
+                game.mode = create.mode;
+                game.started = false;
+                let spectators = if create.spectate {
+                    vec![uuid]
+                } else {
+                    game.add_player(uuid);
+                    vec![]
+                };
+                let (game_uuid, code) =
+                    match spawn_game(game, Some(uuid), create.private, spectators, vec![]) {
+                        Some(spawned) => spawned,
+                        None => return Some(ResponseData::Error(ErrorCode::TooManyGames)),
+                    };
+                with_session(uuid, |session| session.game = Some(game_uuid));
 
-                ResponseData::GameCreateSuccess(game_uuid)
+                ResponseData::GameCreateSuccess {
+                    uuid: game_uuid,
+                    code,
+                }
             }
-        }
+            ClientRequest::JoinGame(join) => {
+                // Private games can only be joined with their code
+                if is_private(join.uuid) {
+                    return Some(ResponseData::Error(ErrorCode::GameNotFound));
+                }
+                return join_game(responder, join.uuid, GameMessage::Join(responder));
+            }
+            ClientRequest::Spectate(spectate) => {
+                if is_private(spectate.uuid) {
+                    return Some(ResponseData::Error(ErrorCode::GameNotFound));
+                }
+                return join_game(responder, spectate.uuid, GameMessage::Spectate(responder));
+            }
+            ClientRequest::Reconnect(reconnect) => {
+                return join_game(
+                    responder,
+                    reconnect.game,
+                    GameMessage::Reconnect(responder, reconnect.token.clone()),
+                );
+            }
+            ClientRequest::JoinByCode(join) => match find_by_code(&join.code) {
+                Some(game_uuid) => {
+                    return join_game(responder, game_uuid, GameMessage::Join(responder))
+                }
+                None => ResponseData::Error(ErrorCode::GameNotFound),
+            },
+            ClientRequest::Kick(kick) => {
+                return forward(responder, GameMessage::Kick(responder, kick.player));
+            }
+            ClientRequest::SetSettings(set) => {
+                return forward(responder, GameMessage::Settings(responder, set.settings));
+            }
+            ClientRequest::Ready(ready) => {
+                return forward(responder, GameMessage::Ready(responder, ready.ready));
+            }
+            ClientRequest::AddAi(_) => {
+                return forward(responder, GameMessage::AddAi(responder));
+            }
+            ClientRequest::StartGame(_) => {
+                return forward(responder, GameMessage::Start(responder));
+            }
+            ClientRequest::ChatMessage(chat) => {
+                if let Some(err) = valid_message(&chat.text) {
+                    return Some(ResponseData::Error(err));
+                }
+
+                return forward(
+                    responder,
+                    GameMessage::Chat(responder, chat.scope, chat.text.clone()),
+                );
+            }
+            ClientRequest::LeaveGame(_) => {
+                match with_session(uuid, |session| session.game.take()).flatten() {
+                    Some(game_uuid) => {
+                        send_game(game_uuid, GameMessage::Leave(uuid));
+                        ResponseData::Success
+                    }
+                    None => ResponseData::Error(ErrorCode::NotInGame),
+                }
+            }
+            ClientRequest::ListGames(_) => ResponseData::GameList(list_games()),
+            ClientRequest::QueueForMatch(_) => {
+                if with_session(uuid, |session| session.game.is_some()) == Some(true) {
+                    return Some(ResponseData::Error(ErrorCode::AlreadyInGame));
+                }
+                if !queue(responder) {
+                    return Some(ResponseData::Error(ErrorCode::AlreadyQueued));
+                }
+                return None;
+            }
+            ClientRequest::LeaveQueue(_) => {
+                if !leave_queue(uuid) {
+                    return Some(ResponseData::Error(ErrorCode::NotQueued));
+                }
+                ResponseData::Success
+            }
+            ClientRequest::MoveWorker(move_worker) => {
+                return send_command(
+                    responder,
+                    Command::MoveWorker {
+                        worker: move_worker.worker,
+                        goal: move_worker.goal,
+                    },
+                );
+            }
+            ClientRequest::AssignOre(assign) => {
+                return send_command(
+                    responder,
+                    Command::AssignOre {
+                        worker: assign.worker,
+                        ore: assign.ore,
+                    },
+                );
+            }
+            ClientRequest::PlaceBuilding(place) => {
+                return send_command(
+                    responder,
+                    Command::PlaceBuilding {
+                        kind: place.kind,
+                        pos: place.pos,
+                    },
+                );
+            }
+            ClientRequest::Cancel(cancel) => {
+                return send_command(
+                    responder,
+                    Command::Cancel {
+                        worker: cancel.worker,
+                    },
+                );
+            }
+            ClientRequest::AckSnapshot(ack) => {
+                if let Some(game_uuid) = with_session(uuid, |session| session.game).flatten() {
+                    send_game(game_uuid, GameMessage::Ack(uuid, ack.tick));
+                }
+                return None;
+            }
+            ClientRequest::Checksum(checksum) => {
+                if let Some(game_uuid) = with_session(uuid, |session| session.game).flatten() {
+                    send_game(
+                        game_uuid,
+                        GameMessage::Checksum(uuid, checksum.turn, checksum.checksum),
+                    );
+                }
+                return None;
+            }
+        };
+        Some(data)
     }
 }
@@ -1,4 +1,7 @@
+pub mod astar;
+pub mod fragment;
 pub mod game;
+pub mod reliable;
 pub mod types_client;
 pub mod types_game;
 pub mod types_server;
@@ -1,121 +1,157 @@
 #[cfg(feature = "server")]
-use std::hash::{Hash, Hasher};
+use ak_server::fragment::MAX_UDP_PAYLOAD;
 #[cfg(feature = "server")]
-use std::net::SocketAddr;
+use tracing::{error, info, warn};
 #[cfg(feature = "server")]
-use std::sync::Mutex;
+use tracing_subscriber::EnvFilter;
 
 #[cfg(feature = "server")]
-use ak_server::game::{in_game, CONN_GAMES};
+use crate::bans::{expire_bans, is_banned, load_bans, strike, Strike};
 #[cfg(feature = "server")]
-use ak_server::types_client::ClientRequest;
+use crate::config::init;
 #[cfg(feature = "server")]
-use ak_server::{hashmap, hashset};
+use crate::console::run_console;
 #[cfg(feature = "server")]
-use chrono::Utc;
+use crate::matchmaking::run_matchmaker;
 #[cfg(feature = "server")]
-use colored::Colorize;
+use crate::metrics::{sample_rates, serve_metrics, traffic};
 #[cfg(feature = "server")]
-use lazy_static::lazy_static;
+use crate::profiles::open_profiles;
 #[cfg(feature = "server")]
-use rustc_hash::{FxHashMap, FxHashSet, FxHasher};
+use crate::session::{close_link, sweep_sessions};
 #[cfg(feature = "server")]
-use tokio::net::UdpSocket;
-
+use crate::transport::{handle_payload, hash_addr, TransportKind};
+#[cfg(feature = "server")]
+use crate::udp::{bind, maintain_links, receive};
 #[cfg(feature = "server")]
-use crate::handle_request::handle_request::handle_request;
+use crate::ws::listen;
 
+#[cfg(feature = "server")]
+mod bans;
+#[cfg(feature = "server")]
+mod config;
+#[cfg(feature = "server")]
+mod console;
+#[cfg(feature = "server")]
+mod games;
 mod handle_request;
-
 #[cfg(feature = "server")]
-lazy_static! {
-    static ref CONN_COUNT: Mutex<FxHashSet<u64>> = Mutex::from(hashset! {});
-    static ref CONN_USERNAMES: Mutex<FxHashMap<u64, String>> = Mutex::from(hashmap! {});
-}
-
+mod matchmaking;
 #[cfg(feature = "server")]
-fn add_username(uuid: u64, username: &str) {
-    let mut usernames = CONN_USERNAMES.lock().unwrap();
-    usernames.insert(uuid, username.to_string());
-}
-
+mod metrics;
 #[cfg(feature = "server")]
-fn hash_addr(addr: SocketAddr) -> u64 {
-    let host = addr.ip();
-    let port = addr.port();
-
-    let mut hasher = FxHasher::default();
-    (host, port).hash(&mut hasher);
-
-    hasher.finish()
-}
+mod profiles;
+#[cfg(feature = "server")]
+mod ratelimit;
+#[cfg(feature = "server")]
+mod session;
+#[cfg(feature = "server")]
+mod transport;
+#[cfg(feature = "server")]
+mod udp;
+#[cfg(feature = "server")]
+mod ws;
 
 #[cfg(feature = "server")]
 #[allow(dead_code)]
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Load the config, exiting before anything starts if it is invalid
+    let config = match init(std::env::args()) {
+        Ok(Some(config)) => config,
+        Ok(None) => return Ok(()),
+        Err(err) => {
+            // Logging isn't set up yet, the level comes from the config
+            eprintln!("Failed to load config; {err}");
+            std::process::exit(1);
+        }
+    };
+
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&config.log_level));
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+
+    // Bans that couldn't be loaded aren't silently dropped
+    match load_bans() {
+        Ok(count) => info!(count, "Loaded bans"),
+        Err(err) => {
+            error!(err, "Failed to load bans");
+            std::process::exit(1);
+        }
+    }
+    tokio::spawn(expire_bans());
+
+    if let Err(err) = open_profiles() {
+        error!(err, "Failed to open profiles");
+        std::process::exit(1);
+    }
+
     // Start listening
-    let socket = UdpSocket::bind("127.0.0.1:8080").await?;
-    println!(
-        "{}",
-        format!("Listening on: {}", socket.local_addr()?).green()
-    );
+    let socket = bind(config.udp_addr()).await?;
+    info!(addr = %socket.local_addr()?, "Listening on UDP");
+
+    // Time out clients that stop sending heartbeats, and resend lost packets
+    tokio::spawn(sweep_sessions());
+    tokio::spawn(maintain_links());
+
+    // Group queued players into matches
+    tokio::spawn(run_matchmaker());
 
-    let mut buf = [0; 1024];
+    // Admin commands from stdin
+    tokio::spawn(run_console());
 
-    // Accept connections and process them, spawning a new tasks for each one
-    loop {
+    // Clients that can't use UDP connect over WebSockets instead
+    tokio::spawn(async {
+        if let Err(err) = listen(config.ws_addr()).await {
+            error!(?err, "WebSocket listener stopped");
+        }
+    });
+
+    // Server health for graphing, only reachable from the machine itself
+    tokio::spawn(sample_rates());
+    if config.metrics {
+        tokio::spawn(async {
+            if let Err(err) = serve_metrics(config.metrics_port).await {
+                error!(?err, "Metrics listener stopped");
+            }
+        });
+    }
+
+    let mut buf = vec![0; MAX_UDP_PAYLOAD];
+
+    // Accept requests and process them
+    'recv: loop {
         let (n, addr) = socket.recv_from(&mut buf).await?;
-        let hash = hash_addr(addr);
-
-        CONN_COUNT.lock().unwrap().insert(hash);
-        println!(
-            "{}",
-            format!(
-                "New connection: {}, total connections: {}",
-                hash,
-                CONN_COUNT.lock().unwrap().len()
-            )
-            .green()
-        );
-
-        // Add username
-        let username = format!("Guest-{}", (hash & 0xFFFF));
-        add_username(hash, &username);
-
-        /// Close a connection, remove it from the connection count, remove player from game if in one, and return
+        traffic(TransportKind::Udp).received(n);
+        if is_banned(addr.ip()) {
+            continue;
+        }
+        let link = hash_addr(TransportKind::Udp, addr);
+
+        /// Close the link, end the sessions routed through it, remove their players from games, and return. With a message it is also a strike against the address
         macro_rules! close_return {
             () => {{
-                CONN_COUNT.lock().unwrap().remove(&hash);
-                if in_game(hash) {
-                    CONN_GAMES.lock().unwrap().remove(&hash);
-                }
-                continue;
+                close_link(link);
+                continue 'recv;
             }};
             ($($arg:tt)*) => {{
-                println!("{}", format!($($arg)*).red());
+                warn!(link, $($arg)*);
+                strike(addr.ip(), Strike::Malformed);
                 close_return!();
             }};
         }
 
-        let raw = &buf[0..n];
-        let request: ClientRequest = match rmp_serde::from_slice(raw) {
-            Ok(response) => response,
+        let payloads = match receive(link, addr, &buf[0..n]) {
+            Ok(payloads) => payloads,
             Err(err) => {
-                close_return!("Failed to deserialize response; {:?}", err);
+                close_return!(?err, "Failed to deserialize packet");
             }
         };
 
-        let now = Utc::now().timestamp_millis() as u64;
-        let ping = now.saturating_sub(request.timestamp());
-        let response = (
-            handle_request(hash, &request),
-            ping.clamp(0, u16::MAX.into()) as u16,
-        );
-        let response = rmp_serde::to_vec(&response).unwrap();
-
-        if let Err(err) = socket.send_to(&response, addr).await {
-            close_return!("Failed to write to socket; {:?}", err);
+        for raw in payloads {
+            if let Err(err) = handle_payload(link, &raw) {
+                close_return!(?err, "Failed to deserialize request");
+            }
         }
     }
 }
@@ -0,0 +1,168 @@
+//! Players waiting for a match are grouped into games here. A match starts as soon as [MAX_PLAYERS] people are queued, or with AI players in the empty slots once the longest waiting player has waited [crate::config::ServerConfig::backfill_wait]
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use ak_server::game::{Game, GameSettings, MAX_PLAYERS};
+use ak_server::types_server::{ErrorCode, ResponseData};
+use ak_server::util::now;
+use lazy_static::lazy_static;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::config::config;
+use crate::games::{send_game, spawn_game, GameMessage};
+use crate::session::with_session;
+use crate::transport::Responder;
+
+/// How often the queue is checked for matches
+const MATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A player waiting for a match, answered through the responder once it starts
+struct Queued {
+    responder: Responder,
+    /// When the player joined the queue, in milliseconds
+    since: u64,
+}
+
+lazy_static! {
+    /// Every player waiting for a match, longest waiting first
+    static ref QUEUE: Mutex<Vec<Queued>> = Mutex::from(vec![]);
+}
+
+/// Adds a connection to the queue, returns `false` if it is already queued
+pub fn queue(responder: Responder) -> bool {
+    let mut queue = QUEUE.lock().unwrap();
+    if queue
+        .iter()
+        .any(|queued| queued.responder.uuid == responder.uuid)
+    {
+        return false;
+    }
+    queue.push(Queued {
+        responder,
+        since: now(),
+    });
+    true
+}
+
+/// Removes a connection from the queue, returns `false` if it wasn't queued
+pub fn leave_queue(uuid: u64) -> bool {
+    let mut queue = QUEUE.lock().unwrap();
+    let len = queue.len();
+    queue.retain(|queued| queued.responder.uuid != uuid);
+    queue.len() != len
+}
+
+pub fn is_queued(uuid: u64) -> bool {
+    QUEUE
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|queued| queued.responder.uuid == uuid)
+}
+
+/// Takes the players of every match that is ready to start off the queue
+fn take_matches() -> Vec<Vec<Queued>> {
+    let mut queue = QUEUE.lock().unwrap();
+    let mut matches = vec![];
+    loop {
+        let waited = queue.first().is_some_and(|queued| {
+            config().backfill_ai
+                && now().saturating_sub(queued.since) >= config().backfill_wait * 1000
+        });
+        if queue.len() < MAX_PLAYERS && !waited {
+            break;
+        }
+
+        let len = queue.len().min(MAX_PLAYERS);
+        matches.push(queue.drain(..len).collect());
+    }
+    matches
+}
+
+/// Starts a game with the queued players, filling the rest with AI players, and tells each player their color. Players that got into another game while queued are left out and their slot goes to an AI player
+fn start_match(players: Vec<Queued>) {
+    let mut game = Game::new();
+    let game_uuid = game.uuid;
+    // Nobody picks the settings for a match, so every match gets its own map
+    game.set_settings(GameSettings {
+        seed: rand::random(),
+        ..game.settings
+    });
+
+    let mut welcome = vec![];
+    for queued in players {
+        let responder = queued.responder;
+        let claimed = with_session(responder.uuid, |session| {
+            if session.game.is_some() {
+                return false;
+            }
+            session.game = Some(game_uuid);
+            true
+        });
+        match claimed {
+            Some(true) => {}
+            Some(false) => {
+                responder.respond(ResponseData::Error(ErrorCode::AlreadyInGame));
+                continue;
+            }
+            // The connection closed since the match was made
+            None => continue,
+        }
+
+        let Some(color) = game.add_player(responder.uuid) else {
+            release(responder.uuid, game_uuid);
+            responder.respond(ResponseData::Error(ErrorCode::GameFull));
+            continue;
+        };
+        welcome.push((
+            responder,
+            ResponseData::MatchFound {
+                uuid: game_uuid,
+                color,
+            },
+        ));
+    }
+    if welcome.is_empty() {
+        return;
+    }
+    while game.add_ai().is_some() {}
+
+    let people: Vec<Responder> = welcome.iter().map(|(responder, _)| *responder).collect();
+    if spawn_game(game, None, false, vec![], welcome).is_none() {
+        for responder in people {
+            release(responder.uuid, game_uuid);
+            responder.respond(ResponseData::Error(ErrorCode::TooManyGames));
+        }
+        return;
+    }
+
+    // Connections that closed before the game was running couldn't tell it they left
+    for responder in &people {
+        if with_session(responder.uuid, |_| ()).is_none() {
+            send_game(game_uuid, GameMessage::Leave(responder.uuid));
+        }
+    }
+    info!(game = %game_uuid, players = people.len(), "Match found");
+}
+
+/// Takes a player back out of a match that didn't start
+fn release(uuid: u64, game_uuid: Uuid) {
+    with_session(uuid, |session| {
+        if session.game == Some(game_uuid) {
+            session.game = None;
+        }
+    });
+}
+
+/// Starts every match that is ready, runs forever
+pub async fn run_matchmaker() {
+    let mut interval = tokio::time::interval(MATCH_INTERVAL);
+    loop {
+        interval.tick().await;
+        for players in take_matches() {
+            start_match(players);
+        }
+    }
+}
@@ -0,0 +1,266 @@
+//! Counters for the server's health, served in the Prometheus text format on localhost so they can be scraped and graphed
+
+use std::fmt::Write;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use ak_server::hashmap;
+use ak_server::types_server::ErrorCode;
+use lazy_static::lazy_static;
+use rustc_hash::FxHashMap;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{info, warn};
+
+use crate::games::game_count;
+use crate::session::session_count;
+use crate::transport::TransportKind;
+
+/// Upper bounds of the request latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 9] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+/// How often the packet rates are sampled
+const RATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Packets and bytes through a transport. WebSocket messages count as packets
+pub struct Traffic {
+    packets_in: AtomicU64,
+    packets_out: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    /// Packets in and out over the last [RATE_INTERVAL]
+    rate_in: AtomicU64,
+    rate_out: AtomicU64,
+}
+impl Traffic {
+    const fn new() -> Traffic {
+        Traffic {
+            packets_in: AtomicU64::new(0),
+            packets_out: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            rate_in: AtomicU64::new(0),
+            rate_out: AtomicU64::new(0),
+        }
+    }
+
+    pub fn received(&self, bytes: usize) {
+        self.packets_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self, bytes: usize) {
+        self.packets_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+static UDP: Traffic = Traffic::new();
+static WEBSOCKET: Traffic = Traffic::new();
+
+/// Returns the counters of a transport
+pub fn traffic(kind: TransportKind) -> &'static Traffic {
+    match kind {
+        TransportKind::Udp => &UDP,
+        TransportKind::WebSocket => &WEBSOCKET,
+    }
+}
+
+/// Latencies of one kind of request
+#[derive(Default)]
+struct Histogram {
+    /// Requests that took at most each of [LATENCY_BUCKETS]
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+lazy_static! {
+    /// Map of every [ak_server::types_client::ClientRequest] kind to how long its responses took
+    static ref REQUESTS: Mutex<FxHashMap<&'static str, Histogram>> = Mutex::from(hashmap! {});
+    /// Map of every [ErrorCode] responded with to how many times
+    static ref ERRORS: Mutex<FxHashMap<String, u64>> = Mutex::from(hashmap! {});
+}
+
+/// Records how long a request took from being received to being answered, games answering it included
+pub fn record_request(kind: &'static str, latency: Duration) {
+    let seconds = latency.as_secs_f64();
+    let mut requests = REQUESTS.lock().unwrap();
+    let histogram = requests.entry(kind).or_default();
+    for (bucket, bound) in histogram.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+        if seconds <= bound {
+            *bucket += 1;
+        }
+    }
+    histogram.sum += seconds;
+    histogram.count += 1;
+}
+
+pub fn record_error(code: ErrorCode) {
+    *ERRORS
+        .lock()
+        .unwrap()
+        .entry(format!("{code:?}"))
+        .or_default() += 1;
+}
+
+/// Samples how many packets went through each transport since the last sample, runs forever
+pub async fn sample_rates() {
+    let mut interval = tokio::time::interval(RATE_INTERVAL);
+    let mut last = [(0, 0); 2];
+    loop {
+        interval.tick().await;
+        for (traffic, last) in [&UDP, &WEBSOCKET].into_iter().zip(last.iter_mut()) {
+            let packets_in = traffic.packets_in.load(Ordering::Relaxed);
+            let packets_out = traffic.packets_out.load(Ordering::Relaxed);
+            traffic
+                .rate_in
+                .store(packets_in - last.0, Ordering::Relaxed);
+            traffic
+                .rate_out
+                .store(packets_out - last.1, Ordering::Relaxed);
+            *last = (packets_in, packets_out);
+        }
+    }
+}
+
+/// Writes every metric in the Prometheus text format
+fn render() -> String {
+    let mut out = String::new();
+
+    macro_rules! metric {
+        ($name:literal, $kind:literal, $help:literal) => {
+            writeln!(out, "# HELP {} {}", $name, $help).unwrap();
+            writeln!(out, "# TYPE {} {}", $name, $kind).unwrap();
+        };
+    }
+
+    metric!("ak_connections", "gauge", "Connections with a session");
+    writeln!(out, "ak_connections {}", session_count()).unwrap();
+    metric!("ak_games", "gauge", "Running games, lobbies included");
+    writeln!(out, "ak_games {}", game_count()).unwrap();
+
+    let transports = [("udp", &UDP), ("websocket", &WEBSOCKET)];
+    macro_rules! traffic_metric {
+        ($name:literal, $kind:literal, $help:literal, $field:ident) => {
+            metric!($name, $kind, $help);
+            for (transport, traffic) in transports {
+                let value = traffic.$field.load(Ordering::Relaxed);
+                writeln!(out, "{}{{transport=\"{transport}\"}} {value}", $name).unwrap();
+            }
+        };
+    }
+    traffic_metric!(
+        "ak_packets_received_total",
+        "counter",
+        "Packets received",
+        packets_in
+    );
+    traffic_metric!(
+        "ak_packets_sent_total",
+        "counter",
+        "Packets sent",
+        packets_out
+    );
+    traffic_metric!(
+        "ak_bytes_received_total",
+        "counter",
+        "Bytes received",
+        bytes_in
+    );
+    traffic_metric!("ak_bytes_sent_total", "counter", "Bytes sent", bytes_out);
+    traffic_metric!(
+        "ak_packets_received_per_second",
+        "gauge",
+        "Packets received over the last second",
+        rate_in
+    );
+    traffic_metric!(
+        "ak_packets_sent_per_second",
+        "gauge",
+        "Packets sent over the last second",
+        rate_out
+    );
+
+    metric!(
+        "ak_request_duration_seconds",
+        "histogram",
+        "Time from receiving a request to answering it, by request kind"
+    );
+    for (kind, histogram) in REQUESTS.lock().unwrap().iter() {
+        for (bucket, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+            writeln!(
+                out,
+                "ak_request_duration_seconds_bucket{{kind=\"{kind}\",le=\"{bound}\"}} {bucket}"
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "ak_request_duration_seconds_bucket{{kind=\"{kind}\",le=\"+Inf\"}} {}",
+            histogram.count
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "ak_request_duration_seconds_sum{{kind=\"{kind}\"}} {}",
+            histogram.sum
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "ak_request_duration_seconds_count{{kind=\"{kind}\"}} {}",
+            histogram.count
+        )
+        .unwrap();
+    }
+
+    metric!(
+        "ak_errors_total",
+        "counter",
+        "Error responses, by error code"
+    );
+    for (code, count) in ERRORS.lock().unwrap().iter() {
+        writeln!(out, "ak_errors_total{{code=\"{code}\"}} {count}").unwrap();
+    }
+
+    out
+}
+
+/// Serves the metrics at `/metrics` on localhost, runs forever
+pub async fn serve_metrics(port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)).await?;
+    info!(addr = %listener.local_addr()?, "Serving metrics");
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream).await {
+                warn!(?err, "Failed to serve metrics");
+            }
+        });
+    }
+}
+
+/// Answers a single HTTP request, only the request line is looked at
+async fn respond(mut stream: TcpStream) -> io::Result<()> {
+    let mut buf = [0; 1024];
+    let n = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+
+    let (status, body) = if path == "/metrics" {
+        ("200 OK", render())
+    } else {
+        ("404 Not Found", String::from("Not found\n"))
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
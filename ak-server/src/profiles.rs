@@ -0,0 +1,218 @@
+//! Player profiles, kept in an embedded database at [crate::config::ServerConfig::profiles_file] so names and stats survive restarts. A session logs into a profile with the secret it was given when the profile was created, only a hash of the secret is stored
+//!
+//! Every function here blocks on the database, so callers on the async runtime run them with [spawn_blocking]
+
+use std::sync::OnceLock;
+
+use ak_server::types_server::{ErrorCode, ProfileInfo, ProfileStats};
+use ak_server::util::now;
+use redb::{Database, ReadableTable, TableDefinition};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::task::{spawn_blocking, JoinHandle};
+use tracing::error;
+use uuid::Uuid;
+
+use crate::config::config;
+
+/// Map of profile ids to their serialized [Profile]
+const PROFILES: TableDefinition<u128, &[u8]> = TableDefinition::new("profiles");
+
+/// Map of lowercase profile names to the id of the profile with the name, keeps names unique without caring about case
+const NAMES: TableDefinition<&str, u128> = TableDefinition::new("names");
+
+/// Names starting with this are given to sessions that aren't logged in, so profiles can't take them
+const GUEST_PREFIX: &str = "guest-";
+
+static DB: OnceLock<Database> = OnceLock::new();
+
+#[derive(Serialize, Deserialize)]
+struct Profile {
+    name: String,
+    created: u64,
+    #[serde(with = "serde_bytes")]
+    secret_hash: Vec<u8>,
+    stats: ProfileStats,
+}
+impl Profile {
+    fn info(self, id: Uuid) -> ProfileInfo {
+        ProfileInfo {
+            id,
+            name: self.name,
+            created: self.created,
+            stats: self.stats,
+        }
+    }
+}
+
+fn hash_secret(secret: &str) -> Vec<u8> {
+    Sha256::digest(secret.as_bytes()).to_vec()
+}
+
+/// Logs a database error, requests that needed it get [ErrorCode::ProfileUnavailable]
+fn unavailable(err: impl Into<redb::Error>) -> ErrorCode {
+    error!(err = %err.into(), "Profile database error");
+    ErrorCode::ProfileUnavailable
+}
+
+/// Opens the database, creating it if it doesn't exist, can only be called once
+pub fn open_profiles() -> Result<(), String> {
+    let path = &config().profiles_file;
+    let fail = |err: redb::Error| format!("Failed to open \"{path}\"; {err}");
+    let db = Database::create(path).map_err(|err| fail(err.into()))?;
+
+    // Reads fail on tables that were never written to, so make sure both exist
+    let txn = db.begin_write().map_err(|err| fail(err.into()))?;
+    txn.open_table(PROFILES).map_err(|err| fail(err.into()))?;
+    txn.open_table(NAMES).map_err(|err| fail(err.into()))?;
+    txn.commit().map_err(|err| fail(err.into()))?;
+
+    if DB.set(db).is_err() {
+        panic!("Profiles already opened");
+    }
+    Ok(())
+}
+
+fn db() -> &'static Database {
+    DB.get().expect("Profiles not opened")
+}
+
+/// Whether a profile has the name, case isn't considered
+pub fn name_taken(name: &str) -> Result<bool, ErrorCode> {
+    let txn = db().begin_read().map_err(unavailable)?;
+    let names = txn.open_table(NAMES).map_err(unavailable)?;
+    let taken = names
+        .get(name.to_lowercase().as_str())
+        .map_err(unavailable)?
+        .is_some();
+    Ok(taken)
+}
+
+/// Creates a profile with a name no other profile has, returns it along with the secret to log into it with
+pub fn create_profile(name: &str) -> Result<(ProfileInfo, String), ErrorCode> {
+    if name.to_lowercase().starts_with(GUEST_PREFIX) {
+        return Err(ErrorCode::NameTaken);
+    }
+
+    let id = Uuid::new_v4();
+    let secret = format!("{:032x}", rand::random::<u128>());
+    let profile = Profile {
+        name: name.to_string(),
+        created: now(),
+        secret_hash: hash_secret(&secret),
+        stats: ProfileStats::default(),
+    };
+
+    let txn = db().begin_write().map_err(unavailable)?;
+    {
+        let mut names = txn.open_table(NAMES).map_err(unavailable)?;
+        let key = name.to_lowercase();
+        if names.get(key.as_str()).map_err(unavailable)?.is_some() {
+            return Err(ErrorCode::NameTaken);
+        }
+        names
+            .insert(key.as_str(), id.as_u128())
+            .map_err(unavailable)?;
+
+        let mut profiles = txn.open_table(PROFILES).map_err(unavailable)?;
+        let raw = rmp_serde::to_vec(&profile).unwrap();
+        profiles
+            .insert(id.as_u128(), raw.as_slice())
+            .map_err(unavailable)?;
+    }
+    txn.commit().map_err(unavailable)?;
+
+    Ok((profile.info(id), secret))
+}
+
+fn read(id: Uuid) -> Result<Option<Profile>, ErrorCode> {
+    let txn = db().begin_read().map_err(unavailable)?;
+    let profiles = txn.open_table(PROFILES).map_err(unavailable)?;
+    let Some(raw) = profiles.get(id.as_u128()).map_err(unavailable)? else {
+        return Ok(None);
+    };
+    Ok(rmp_serde::from_slice(raw.value()).ok())
+}
+
+/// Returns a profile if the secret is the one it was created with
+pub fn login(id: Uuid, secret: &str) -> Result<ProfileInfo, ErrorCode> {
+    match read(id)? {
+        Some(profile) if profile.secret_hash == hash_secret(secret) => Ok(profile.info(id)),
+        _ => Err(ErrorCode::ProfileNotFound),
+    }
+}
+
+pub fn profile(id: Uuid) -> Result<ProfileInfo, ErrorCode> {
+    read(id)?
+        .map(|profile| profile.info(id))
+        .ok_or(ErrorCode::ProfileNotFound)
+}
+
+/// Reads a profile, changes it and writes it back in one transaction, along with anything else `change` does with the names table
+fn update(
+    id: Uuid,
+    change: impl FnOnce(&mut Profile, &mut redb::Table<&str, u128>) -> Result<(), ErrorCode>,
+) -> Result<(), ErrorCode> {
+    let txn = db().begin_write().map_err(unavailable)?;
+    {
+        let mut profiles = txn.open_table(PROFILES).map_err(unavailable)?;
+        let mut profile: Profile = {
+            let raw = profiles
+                .get(id.as_u128())
+                .map_err(unavailable)?
+                .ok_or(ErrorCode::ProfileNotFound)?;
+            rmp_serde::from_slice(raw.value()).map_err(|_| ErrorCode::ProfileNotFound)?
+        };
+
+        let mut names = txn.open_table(NAMES).map_err(unavailable)?;
+        change(&mut profile, &mut names)?;
+
+        let raw = rmp_serde::to_vec(&profile).unwrap();
+        profiles
+            .insert(id.as_u128(), raw.as_slice())
+            .map_err(unavailable)?;
+    }
+    txn.commit().map_err(unavailable)
+}
+
+/// Renames a profile, the new name can't belong to another profile
+pub fn rename_profile(id: Uuid, name: &str) -> Result<(), ErrorCode> {
+    if name.to_lowercase().starts_with(GUEST_PREFIX) {
+        return Err(ErrorCode::NameTaken);
+    }
+
+    update(id, |profile, names| {
+        let key = name.to_lowercase();
+        let owner = names
+            .get(key.as_str())
+            .map_err(unavailable)?
+            .map(|owner| owner.value());
+        if owner.is_some_and(|owner| owner != id.as_u128()) {
+            return Err(ErrorCode::NameTaken);
+        }
+
+        names
+            .remove(profile.name.to_lowercase().as_str())
+            .map_err(unavailable)?;
+        names
+            .insert(key.as_str(), id.as_u128())
+            .map_err(unavailable)?;
+        profile.name = name.to_string();
+        Ok(())
+    })
+}
+
+/// Adds a finished game to a profile's stats on a blocking thread, so the game's task isn't held up. Failing only logs as the game is already over
+pub fn record_game(id: Uuid, won: bool, gathered: u64) -> JoinHandle<()> {
+    spawn_blocking(move || {
+        let result = update(id, |profile, _| {
+            profile.stats.games_played += 1;
+            profile.stats.wins += won as u32;
+            profile.stats.gathered += gathered;
+            Ok(())
+        });
+        if let Err(err) = result {
+            error!(profile = %id, ?err, "Failed to record game");
+        }
+    })
+}
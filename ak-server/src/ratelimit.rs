@@ -0,0 +1,156 @@
+//! Token bucket ratelimits, one bucket per connection and [ClientRequest] kind. Buckets are measured against the server's clock, so clients can't get around them by faking request timestamps
+
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use ak_server::hashmap;
+use ak_server::types_client::ClientRequest;
+use ak_server::util::now;
+use lazy_static::lazy_static;
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::config::config;
+use crate::session::with_session;
+
+/// How many requests of a kind can be sent at once, and how quickly that allowance comes back
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct RateLimit {
+    /// Most requests that can be sent back to back, the size of the bucket
+    pub burst: u32,
+    /// Requests added back to the bucket every second
+    pub per_second: f64,
+}
+impl RateLimit {
+    pub const fn new(burst: u32, per_second: f64) -> RateLimit {
+        RateLimit { burst, per_second }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TokenBucket {
+    tokens: f64,
+    /// When the bucket was last refilled, in milliseconds
+    last: u64,
+}
+impl TokenBucket {
+    /// Creates a full bucket
+    pub fn new(limit: RateLimit, now: u64) -> TokenBucket {
+        TokenBucket {
+            tokens: limit.burst as f64,
+            last: now,
+        }
+    }
+
+    /// Refills the bucket for the time since it was last used and takes a token, returns `false` if there wasn't one
+    pub fn take(&mut self, limit: RateLimit, now: u64) -> bool {
+        let elapsed = now.saturating_sub(self.last) as f64 / 1000.0;
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst as f64);
+        self.last = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// Whether the bucket would be full by now, in which case it is no different from a new one
+    fn full(&self, limit: RateLimit, now: u64) -> bool {
+        let elapsed = now.saturating_sub(self.last) as f64 / 1000.0;
+        self.tokens + elapsed * limit.per_second >= limit.burst as f64
+    }
+}
+
+/// Where a request came from, for requests limited before or beyond their session
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Source {
+    Link(u64),
+    Address(IpAddr),
+}
+
+lazy_static! {
+    /// Map of every source and request kind to its bucket, kept apart from the sessions' buckets as these outlive sessions
+    static ref SOURCE_BUCKETS: Mutex<FxHashMap<(Source, &'static str), TokenBucket>> = Mutex::from(hashmap! {});
+}
+
+/// Takes a token from the connection's bucket for the request's kind, returns `false` if the request is over its ratelimit. Requests that aren't ratelimited and connections without a session always pass
+pub fn allow(uuid: u64, request: &ClientRequest) -> bool {
+    if !request.ratelimited() {
+        return true;
+    }
+
+    let kind = request.kind();
+    let limit = config().ratelimit(kind);
+    let now = now();
+    with_session(uuid, |session| {
+        session
+            .buckets
+            .entry(kind)
+            .or_insert_with(|| TokenBucket::new(limit, now))
+            .take(limit, now)
+    })
+    .unwrap_or(true)
+}
+
+/// Takes a token from the source's bucket for a request kind, returns `false` if the source is over the kind's ratelimit. Uses the same [crate::config::ServerConfig::ratelimits] as sessions do
+pub fn allow_source(source: Source, kind: &'static str) -> bool {
+    let limit = config().ratelimit(kind);
+    let now = now();
+    SOURCE_BUCKETS
+        .lock()
+        .unwrap()
+        .entry((source, kind))
+        .or_insert_with(|| TokenBucket::new(limit, now))
+        .take(limit, now)
+}
+
+/// Forgets source buckets that have filled back up, so sources that stopped sending don't pile up
+pub fn prune_buckets() {
+    let now = now();
+    SOURCE_BUCKETS
+        .lock()
+        .unwrap()
+        .retain(|(_, kind), bucket| !bucket.full(config().ratelimit(kind), now));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: RateLimit = RateLimit::new(3, 2.0);
+
+    #[test]
+    fn burst() {
+        let mut bucket = TokenBucket::new(LIMIT, 0);
+        for _ in 0..LIMIT.burst {
+            assert!(bucket.take(LIMIT, 0));
+        }
+        assert!(!bucket.take(LIMIT, 0));
+    }
+
+    #[test]
+    fn refills() {
+        let mut bucket = TokenBucket::new(LIMIT, 0);
+        for _ in 0..LIMIT.burst {
+            bucket.take(LIMIT, 0);
+        }
+        // A token comes back every 500ms
+        assert!(!bucket.take(LIMIT, 499));
+        assert!(bucket.take(LIMIT, 500));
+        assert!(!bucket.take(LIMIT, 500));
+        assert!(bucket.take(LIMIT, 1_000));
+    }
+
+    #[test]
+    fn clamps_at_burst() {
+        let mut bucket = TokenBucket::new(LIMIT, 0);
+        bucket.take(LIMIT, 0);
+        let later = 60_000;
+        assert!(bucket.full(LIMIT, later));
+        for _ in 0..LIMIT.burst {
+            assert!(bucket.take(LIMIT, later));
+        }
+        assert!(!bucket.take(LIMIT, later));
+    }
+}
@@ -0,0 +1,255 @@
+//! Reliability on top of UDP. Commands are sent over a reliable channel which numbers, acks, resends and orders them, while frequent state is sent over an unreliable channel that only drops stale packets
+
+use std::collections::{BTreeMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+/// How long to wait for an ack before resending a reliable packet, in milliseconds
+pub const RESEND_TIMEOUT: u64 = 200;
+
+/// How far ahead of the next expected reliable packet others are buffered, anything further is dropped and resent later
+const RECEIVE_WINDOW: u32 = 256;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PacketBody {
+    /// Resent until acked, delivered exactly once and in order
+    Reliable {
+        seq: u32,
+        #[serde(with = "serde_bytes")]
+        payload: Vec<u8>,
+    },
+    /// Sent once, dropped if a newer one has already been received
+    Unreliable {
+        seq: u32,
+        #[serde(with = "serde_bytes")]
+        payload: Vec<u8>,
+    },
+    /// Acknowledges every reliable packet before `next`
+    Ack { next: u32 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Packet {
+    /// Id of the sending [Channel], a new id means the other side restarted
+    pub channel: u32,
+    pub body: PacketBody,
+}
+
+/// A reliable packet that hasn't been acked yet
+#[derive(Debug, Clone)]
+struct Unacked {
+    seq: u32,
+    payload: Vec<u8>,
+    sent_at: u64,
+}
+
+/// One side of a connection, keeps track of sequence numbers, acks and buffered packets. Doesn't do any IO, so the same channel is used by the server and the client
+///
+/// Times are unix timestamps in milliseconds
+#[derive(Debug, Clone)]
+pub struct Channel {
+    /// Random id sent with every packet
+    id: u32,
+    /// Id of the other side's channel, once known
+    remote_id: Option<u32>,
+
+    next_reliable: u32,
+    next_unreliable: u32,
+    unacked: VecDeque<Unacked>,
+
+    /// Next reliable packet that can be delivered
+    next_expected: u32,
+    /// Reliable packets that arrived before the ones preceding them
+    out_of_order: BTreeMap<u32, Vec<u8>>,
+    /// Newest unreliable packet received
+    last_unreliable: Option<u32>,
+}
+impl Default for Channel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Channel {
+    pub fn new() -> Channel {
+        Channel {
+            id: rand::random(),
+            remote_id: None,
+            next_reliable: 0,
+            next_unreliable: 0,
+            unacked: VecDeque::new(),
+            next_expected: 0,
+            out_of_order: BTreeMap::new(),
+            last_unreliable: None,
+        }
+    }
+
+    fn packet(&self, body: PacketBody) -> Packet {
+        Packet {
+            channel: self.id,
+            body,
+        }
+    }
+
+    /// Returns a reliable packet for the payload, it will be returned from [Self::resend] until acked
+    pub fn send_reliable(&mut self, payload: Vec<u8>, now: u64) -> Packet {
+        let seq = self.next_reliable;
+        self.next_reliable += 1;
+
+        self.unacked.push_back(Unacked {
+            seq,
+            payload: payload.clone(),
+            sent_at: now,
+        });
+        self.packet(PacketBody::Reliable { seq, payload })
+    }
+
+    /// Returns an unreliable packet for the payload
+    pub fn send_unreliable(&mut self, payload: Vec<u8>) -> Packet {
+        let seq = self.next_unreliable;
+        self.next_unreliable += 1;
+        self.packet(PacketBody::Unreliable { seq, payload })
+    }
+
+    /// Returns every reliable packet that hasn't been acked within [RESEND_TIMEOUT]
+    pub fn resend(&mut self, now: u64) -> Vec<Packet> {
+        let mut packets = vec![];
+        for unacked in self.unacked.iter_mut() {
+            if unacked.sent_at + RESEND_TIMEOUT <= now {
+                unacked.sent_at = now;
+                packets.push(Packet {
+                    channel: self.id,
+                    body: PacketBody::Reliable {
+                        seq: unacked.seq,
+                        payload: unacked.payload.clone(),
+                    },
+                });
+            }
+        }
+        packets
+    }
+
+    /// Amount of reliable packets waiting for an ack
+    pub fn pending(&self) -> usize {
+        self.unacked.len()
+    }
+
+    /// Handles a packet from the other side. Returns the payloads that are ready, in order, and an ack to send back if needed
+    pub fn receive(&mut self, packet: Packet) -> (Vec<Vec<u8>>, Option<Packet>) {
+        // Other side restarted, so start over with it
+        if self.remote_id != Some(packet.channel) {
+            if self.remote_id.is_some() {
+                *self = Channel {
+                    id: self.id,
+                    ..Channel::new()
+                };
+            }
+            self.remote_id = Some(packet.channel);
+        }
+
+        match packet.body {
+            PacketBody::Reliable { seq, payload } => {
+                if seq >= self.next_expected && seq - self.next_expected < RECEIVE_WINDOW {
+                    self.out_of_order.insert(seq, payload);
+                }
+
+                let mut ready = vec![];
+                while let Some(payload) = self.out_of_order.remove(&self.next_expected) {
+                    ready.push(payload);
+                    self.next_expected += 1;
+                }
+
+                let ack = self.packet(PacketBody::Ack {
+                    next: self.next_expected,
+                });
+                (ready, Some(ack))
+            }
+            PacketBody::Unreliable { seq, payload } => {
+                if self.last_unreliable.is_some_and(|last| seq <= last) {
+                    return (vec![], None);
+                }
+                self.last_unreliable = Some(seq);
+                (vec![payload], None)
+            }
+            PacketBody::Ack { next } => {
+                self.unacked.retain(|unacked| unacked.seq >= next);
+                (vec![], None)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends reliable packets from `sender`, returning them without delivering
+    fn send(sender: &mut Channel, payloads: &[u8], now: u64) -> Vec<Packet> {
+        payloads
+            .iter()
+            .map(|payload| sender.send_reliable(vec![*payload], now))
+            .collect()
+    }
+
+    #[test]
+    fn delivers_in_order() {
+        let (mut sender, mut receiver) = (Channel::new(), Channel::new());
+        let mut delivered = vec![];
+        for packet in send(&mut sender, &[1, 2, 3], 0) {
+            delivered.extend(receiver.receive(packet).0);
+        }
+        assert_eq!(delivered, vec![vec![1], vec![2], vec![3]]);
+    }
+
+    #[test]
+    fn suppresses_duplicates() {
+        let (mut sender, mut receiver) = (Channel::new(), Channel::new());
+        let packet = sender.send_reliable(vec![1], 0);
+        assert_eq!(receiver.receive(packet.clone()).0, vec![vec![1]]);
+
+        let (ready, ack) = receiver.receive(packet);
+        assert!(ready.is_empty());
+        // Duplicates are still acked, the first ack might have been lost
+        assert!(matches!(ack.unwrap().body, PacketBody::Ack { next: 1 }));
+    }
+
+    #[test]
+    fn buffers_out_of_order() {
+        let (mut sender, mut receiver) = (Channel::new(), Channel::new());
+        let packets = send(&mut sender, &[1, 2, 3], 0);
+
+        assert!(receiver.receive(packets[2].clone()).0.is_empty());
+        assert!(receiver.receive(packets[1].clone()).0.is_empty());
+        assert_eq!(
+            receiver.receive(packets[0].clone()).0,
+            vec![vec![1], vec![2], vec![3]]
+        );
+    }
+
+    #[test]
+    fn acks_clear_resends() {
+        let (mut sender, mut receiver) = (Channel::new(), Channel::new());
+        let packets = send(&mut sender, &[1, 2], 0);
+        let ack = receiver.receive(packets[0].clone()).1.unwrap();
+        sender.receive(ack);
+
+        assert_eq!(sender.pending(), 1);
+        let resent = sender.resend(RESEND_TIMEOUT);
+        assert_eq!(resent.len(), 1);
+        assert!(matches!(
+            resent[0].body,
+            PacketBody::Reliable { seq: 1, .. }
+        ));
+    }
+
+    #[test]
+    fn resends_after_timeout() {
+        let mut sender = Channel::new();
+        send(&mut sender, &[1], 1000);
+
+        assert!(sender.resend(1000 + RESEND_TIMEOUT - 1).is_empty());
+        assert_eq!(sender.resend(1000 + RESEND_TIMEOUT).len(), 1);
+        // Resending restarts the timeout
+        assert!(sender.resend(1000 + RESEND_TIMEOUT + 1).is_empty());
+        assert_eq!(sender.resend(1000 + RESEND_TIMEOUT * 2).len(), 1);
+    }
+}
@@ -0,0 +1,186 @@
+//! Tracks connected clients and everything the server knows about them, and times out the ones that go silent
+
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use ak_server::hashmap;
+use ak_server::types_client::SessionToken;
+use ak_server::types_server::ServerMessage;
+use ak_server::util::now;
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use rustc_hash::FxHashMap;
+use sha2::Sha256;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::config::config;
+use crate::games::{send_game, GameMessage};
+use crate::matchmaking::leave_queue;
+use crate::ratelimit::{prune_buckets, TokenBucket};
+use crate::transport::{self, routed_through, send, unroute};
+
+/// How long a client can go without sending anything before its session is closed, in milliseconds
+pub const SESSION_TIMEOUT: u64 = 10_000;
+
+/// How often sessions are checked for timeouts
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct Session {
+    /// Last time any request was received from the client
+    pub last_seen: u64,
+    pub username: String,
+    /// Map of request kinds to the session's ratelimit bucket for them, see [crate::ratelimit]
+    pub buckets: FxHashMap<&'static str, TokenBucket>,
+    /// The game the client is in, if any
+    pub game: Option<Uuid>,
+    /// The profile the client is logged into, see [crate::profiles]
+    pub profile: Option<Uuid>,
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+lazy_static! {
+    /// Key session tokens are signed with. Sessions don't outlive the server, so neither does the key
+    static ref TOKEN_KEY: [u8; 32] = rand::random();
+    /// Map of every connection with a session to the session
+    pub static ref SESSIONS: Mutex<FxHashMap<u64, Session>> = Mutex::from(hashmap! {});
+}
+
+fn token_mac(uuid: u64) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(TOKEN_KEY.as_slice()).unwrap();
+    mac.update(&uuid.to_be_bytes());
+    mac
+}
+
+/// Signs a session's id into the token its client sends with every request
+pub fn issue_token(uuid: u64) -> SessionToken {
+    SessionToken {
+        session: uuid,
+        signature: token_mac(uuid).finalize().into_bytes().to_vec(),
+    }
+}
+
+/// Returns the session a token is for, `None` if this server didn't sign it
+pub fn verify_token(token: &SessionToken) -> Option<u64> {
+    token_mac(token.session)
+        .verify_slice(&token.signature)
+        .ok()
+        .map(|_| token.session)
+}
+
+/// Starts a session for a connection and gives it a guest username. Does nothing if the connection already has one, returns `false` if the server is full
+pub fn connect(uuid: u64) -> bool {
+    let mut sessions = SESSIONS.lock().unwrap();
+    if let Some(session) = sessions.get_mut(&uuid) {
+        session.last_seen = now();
+        return true;
+    }
+    if sessions.len() >= config().max_connections {
+        return false;
+    }
+
+    sessions.insert(
+        uuid,
+        Session {
+            last_seen: now(),
+            username: format!("Guest-{}", (uuid & 0xFFFF)),
+            buckets: hashmap! {},
+            game: None,
+            profile: None,
+        },
+    );
+    let total = sessions.len();
+    drop(sessions);
+
+    info!(connection = uuid, total, "New connection");
+    true
+}
+
+pub fn session_count() -> usize {
+    SESSIONS.lock().unwrap().len()
+}
+
+/// Marks a connection as active, returns `false` if it has no session
+pub fn touch(uuid: u64) -> bool {
+    match SESSIONS.lock().unwrap().get_mut(&uuid) {
+        Some(session) => {
+            session.last_seen = now();
+            true
+        }
+        None => false,
+    }
+}
+
+/// Runs a function on a connection's session, returns `None` if it has no session
+pub fn with_session<T>(uuid: u64, f: impl FnOnce(&mut Session) -> T) -> Option<T> {
+    SESSIONS.lock().unwrap().get_mut(&uuid).map(f)
+}
+
+/// Ends a connection's session, taking it out of the matchmaking queue. Its game is told the connection dropped, players of started games keep their slot for a while to reconnect
+pub fn close_connection(uuid: u64) {
+    unroute(uuid);
+    leave_queue(uuid);
+    let session = SESSIONS.lock().unwrap().remove(&uuid);
+    if let Some(game_uuid) = session.and_then(|session| session.game) {
+        send_game(game_uuid, GameMessage::Dropped(uuid));
+    }
+}
+
+/// Ends the session of every connection routed through a link, for when the link closes or sends something malformed
+pub fn close_link(link: u64) {
+    for uuid in routed_through(link) {
+        close_connection(uuid);
+    }
+}
+
+/// Takes a connection out of its game for good, tells it why and ends its session. Returns `false` if it has no session
+pub fn kick(uuid: u64, reason: &str) -> bool {
+    let Some(game) = with_session(uuid, |session| session.game.take()) else {
+        return false;
+    };
+    if let Some(game_uuid) = game {
+        send_game(game_uuid, GameMessage::Leave(uuid));
+    }
+    send(
+        uuid,
+        rmp_serde::to_vec(&ServerMessage::Closed(reason.to_string())).unwrap(),
+    );
+    close_connection(uuid);
+    info!(connection = uuid, reason, "Kicked");
+    true
+}
+
+/// Kicks every connection from an address, returns how many were kicked
+pub fn kick_address(ip: IpAddr, reason: &str) -> usize {
+    let uuids: Vec<u64> = SESSIONS.lock().unwrap().keys().copied().collect();
+    uuids
+        .into_iter()
+        .filter(|uuid| transport::ip(*uuid) == Some(ip))
+        .filter(|uuid| kick(*uuid, reason))
+        .count()
+}
+
+/// Closes every session that hasn't been heard from in [SESSION_TIMEOUT] and forgets full source buckets, runs forever
+pub async fn sweep_sessions() {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let cutoff = now().saturating_sub(SESSION_TIMEOUT);
+        let expired: Vec<u64> = SESSIONS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, session)| session.last_seen < cutoff)
+            .map(|(uuid, _)| *uuid)
+            .collect();
+
+        for uuid in expired {
+            close_connection(uuid);
+            warn!(connection = uuid, "Connection timed out");
+        }
+        prune_buckets();
+    }
+}
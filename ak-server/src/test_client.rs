@@ -2,30 +2,103 @@ use std::net::{SocketAddr, UdpSocket};
 use std::thread::sleep;
 use std::time::Duration;
 
-use ak_server::types_client::{ClientRequest, Ping};
-use ak_server::types_server::ServerResponse;
-use chrono::Utc;
+use ak_server::fragment::{Fragmenter, DEFAULT_MAX_DATAGRAM, MAX_UDP_PAYLOAD};
+use ak_server::reliable::{Channel, Packet, RESEND_TIMEOUT};
+use ak_server::types_client::{ClientRequest, Connect, Ping, RequestEnvelope, SessionToken};
+use ak_server::types_server::{ResponseData, ServerMessage};
+use ak_server::util::now;
+
+struct Client {
+    socket: UdpSocket,
+    remote_addr: SocketAddr,
+    channel: Channel,
+    fragmenter: Fragmenter,
+    next_id: u32,
+    /// Token of the session, given in response to connecting
+    session: Option<SessionToken>,
+}
+impl Client {
+    fn send_packet(&mut self, packet: &Packet) {
+        let raw = rmp_serde::to_vec(packet).unwrap();
+        for datagram in self.fragmenter.split(&raw).expect("Packet too big") {
+            self.socket.send_to(&datagram, self.remote_addr).unwrap();
+        }
+    }
+
+    /// Sends a request over the reliable channel and waits for the response, resending it if lost
+    fn request(&mut self, request: ClientRequest) {
+        let mut envelope = RequestEnvelope::new(self.next_id, request);
+        envelope.token = self.session.clone();
+        let payload = rmp_serde::to_vec(&envelope).unwrap();
+        self.next_id += 1;
+
+        let packet = self.channel.send_reliable(payload, now());
+        self.send_packet(&packet);
+
+        let mut buf = vec![0; MAX_UDP_PAYLOAD];
+        loop {
+            let n = match self.socket.recv_from(&mut buf) {
+                Ok((n, _)) => n,
+                Err(_) => {
+                    for packet in self.channel.resend(now()) {
+                        self.send_packet(&packet);
+                    }
+                    continue;
+                }
+            };
+            let filled_buf = &buf[..n];
+
+            println!("line: {filled_buf:?}");
+
+            let raw = match self.fragmenter.join(filled_buf, now()).unwrap() {
+                Some(raw) => raw,
+                None => continue,
+            };
+            let packet = rmp_serde::from_slice::<Packet>(&raw).unwrap();
+            let (payloads, ack) = self.channel.receive(packet);
+            if let Some(ack) = ack {
+                self.send_packet(&ack);
+            }
+
+            for payload in payloads {
+                // Snapshots only arrive when in a game, and aren't what's being waited for
+                if let ServerMessage::Response(response) =
+                    rmp_serde::from_slice::<ServerMessage>(&payload).unwrap()
+                {
+                    println!(
+                        "#{}: {:?} (ping: {})",
+                        response.id, response.data, response.ping
+                    );
+                    if let ResponseData::Connected(token) = response.data {
+                        self.session = Some(token);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+}
 
 pub fn main() {
-    let remote_addr: SocketAddr = "127.0.0.1:8080".parse().unwrap();
     let socket = UdpSocket::bind("127.0.0.1:8081").expect("Could not bind socket");
+    socket
+        .set_read_timeout(Some(Duration::from_millis(RESEND_TIMEOUT)))
+        .unwrap();
 
-    loop {
-        // Send a ping
-        let test_payload = ClientRequest::Ping(Ping {
-            timestamp: Utc::now().timestamp_millis() as u64,
-        });
-        let test_payload = rmp_serde::to_vec(&test_payload).unwrap();
-        socket.send_to(&test_payload, remote_addr).unwrap();
-
-        let mut buf = [0; 1024];
-        let (n, _) = socket.recv_from(&mut buf).expect("Didn't receive data");
-        let filled_buf = &mut buf[..n];
+    let mut client = Client {
+        socket,
+        remote_addr: "127.0.0.1:8080".parse().unwrap(),
+        channel: Channel::new(),
+        fragmenter: Fragmenter::new(DEFAULT_MAX_DATAGRAM),
+        next_id: 0,
+        session: None,
+    };
 
-        println!("line: {filled_buf:?}");
+    client.request(ClientRequest::Connect(Connect { timestamp: now() }));
 
-        let (data, ping) = rmp_serde::from_slice::<ServerResponse>(filled_buf).unwrap();
-        println!("{data:?} (ping: {ping})");
+    loop {
+        // Send a ping, which also keeps the session alive
+        client.request(ClientRequest::Ping(Ping { timestamp: now() }));
 
         sleep(Duration::from_millis(1500));
     }
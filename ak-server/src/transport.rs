@@ -0,0 +1,293 @@
+//! Transports clients can connect over. Sessions and games don't care which one a connection uses, only how bytes reach the client differs
+//!
+//! Each address sending to the server gets a link, and each session is routed to the link its last request came from. Sessions are identified by their [SessionToken] rather than their address, so a client whose address changes keeps its session
+
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use ak_server::hashmap;
+use ak_server::types_client::{ClientRequest, RequestEnvelope, SessionToken, PROTOCOL_VERSION};
+use ak_server::types_server::{ErrorCode, ResponseData, ServerMessage, ServerResponse};
+use ak_server::util::now;
+use lazy_static::lazy_static;
+use rustc_hash::{FxHashMap, FxHasher};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::debug;
+
+use crate::bans::{strike, Strike};
+use crate::config::config;
+use crate::handle_request::handle_request::handle_request;
+use crate::metrics::{record_error, record_request};
+use crate::ratelimit::{allow_source, Source};
+use crate::session::{verify_token, with_session};
+use crate::udp;
+
+#[derive(Hash, Clone, Copy, Debug)]
+pub enum TransportKind {
+    Udp,
+    WebSocket,
+}
+
+/// How a link is reached
+#[derive(Clone)]
+pub enum Transport {
+    /// Through the UDP socket, see [crate::udp]
+    Udp,
+    /// Through a WebSocket, messages are handed to the task writing to it
+    WebSocket(UnboundedSender<Vec<u8>>),
+}
+
+lazy_static! {
+    /// Map of every link to how it is reached and the address it is from
+    static ref TRANSPORTS: Mutex<FxHashMap<u64, (Transport, IpAddr)>> = Mutex::from(hashmap! {});
+    /// Map of every session to the link its last request came from
+    static ref ROUTES: Mutex<FxHashMap<u64, u64>> = Mutex::from(hashmap! {});
+}
+
+/// Hashes an address into the id of its link, the kind is included so a UDP and WebSocket link from the same address don't collide. Only links are keyed by address, sessions are keyed by their token
+pub fn hash_addr(kind: TransportKind, addr: SocketAddr) -> u64 {
+    let host = addr.ip();
+    let port = addr.port();
+
+    let mut hasher = FxHasher::default();
+    (kind, host, port).hash(&mut hasher);
+
+    hasher.finish()
+}
+
+/// Sets how a link is reached
+pub fn register(link: u64, transport: Transport, ip: IpAddr) {
+    TRANSPORTS.lock().unwrap().insert(link, (transport, ip));
+}
+
+/// Forgets how a link is reached, anything sent to it afterwards is dropped
+pub fn unregister(link: u64) {
+    TRANSPORTS.lock().unwrap().remove(&link);
+}
+
+/// Sends everything for a session through a link from now on
+fn route(uuid: u64, link: u64) {
+    ROUTES.lock().unwrap().insert(uuid, link);
+}
+
+/// Stops sending anything to a session, for when it ends
+pub fn unroute(uuid: u64) {
+    ROUTES.lock().unwrap().remove(&uuid);
+}
+
+/// Returns every session routed through a link
+pub fn routed_through(link: u64) -> Vec<u64> {
+    ROUTES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, routed)| **routed == link)
+        .map(|(uuid, _)| *uuid)
+        .collect()
+}
+
+/// Whether any session is routed through a link
+pub fn is_routed(link: u64) -> bool {
+    ROUTES
+        .lock()
+        .unwrap()
+        .values()
+        .any(|routed| *routed == link)
+}
+
+/// Returns how many sessions are routed through a link, and how many through any link from the same address
+fn session_counts(link: u64) -> (usize, usize) {
+    let routes = ROUTES.lock().unwrap();
+    let transports = TRANSPORTS.lock().unwrap();
+    let ip = transports.get(&link).map(|(_, ip)| *ip);
+    routes
+        .values()
+        .fold((0, 0), |(on_link, on_address), routed| {
+            let same_address = ip.is_some() && transports.get(routed).map(|(_, ip)| *ip) == ip;
+            (
+                on_link + (*routed == link) as usize,
+                on_address + same_address as usize,
+            )
+        })
+}
+
+/// Returns the link a session is routed through and how it is reached
+fn transport(uuid: u64) -> Option<(u64, Transport)> {
+    let link = *ROUTES.lock().unwrap().get(&uuid)?;
+    let transport = TRANSPORTS.lock().unwrap().get(&link)?.0.clone();
+    Some((link, transport))
+}
+
+/// Returns the address a link is from, `None` if it isn't registered
+fn link_ip(link: u64) -> Option<IpAddr> {
+    TRANSPORTS.lock().unwrap().get(&link).map(|(_, ip)| *ip)
+}
+
+/// Returns the address a session's last request came from, `None` if it isn't routed anywhere
+pub fn ip(uuid: u64) -> Option<IpAddr> {
+    let link = *ROUTES.lock().unwrap().get(&uuid)?;
+    link_ip(link)
+}
+
+/// Sends a message straight to a link, for responses to requests without a session
+fn send_link(link: u64, payload: Vec<u8>) {
+    let transport = TRANSPORTS
+        .lock()
+        .unwrap()
+        .get(&link)
+        .map(|(transport, _)| transport.clone());
+    match transport {
+        Some(Transport::Udp) => udp::send(link, payload),
+        Some(Transport::WebSocket(sender)) => {
+            let _ = sender.send(payload);
+        }
+        None => {}
+    }
+}
+
+/// Sends a message to a session, reliably and in order
+pub fn send(uuid: u64, payload: Vec<u8>) {
+    match transport(uuid) {
+        Some((link, Transport::Udp)) => udp::send(link, payload),
+        Some((_, Transport::WebSocket(sender))) => {
+            // Only fails if the socket is already closing
+            let _ = sender.send(payload);
+        }
+        None => {}
+    }
+}
+
+/// Sends a message to a session without resending it if lost, for things that are soon replaced anyway
+pub fn send_unreliable(uuid: u64, payload: Vec<u8>) {
+    match transport(uuid) {
+        Some((link, Transport::Udp)) => udp::send_unreliable(link, payload),
+        // WebSockets are always reliable
+        Some((_, Transport::WebSocket(sender))) => {
+            let _ = sender.send(payload);
+        }
+        None => {}
+    }
+}
+
+/// Where the response to a request goes. Requests that are answered by a game are handed to it along with their responder
+#[derive(Debug, Clone, Copy)]
+pub struct Responder {
+    pub uuid: u64,
+    id: u32,
+    ping: u64,
+    /// Kind of the request, see [ak_server::types_client::ClientRequest::kind]
+    kind: &'static str,
+    /// When the request was received, for its latency
+    received: Instant,
+}
+impl Responder {
+    /// Sends the response to the request and records how long it took
+    pub fn respond(self, data: ResponseData) {
+        record_request(self.kind, self.received.elapsed());
+        if let ResponseData::Error(code) = data {
+            record_error(code);
+        }
+        let response = ServerMessage::Response(ServerResponse {
+            id: self.id,
+            data,
+            ping: self.ping.clamp(0, u16::MAX.into()) as u16,
+        });
+        send(self.uuid, rmp_serde::to_vec(&response).unwrap());
+    }
+}
+
+/// Answers a request that has no session to respond through, straight over the link it came from
+fn reject(link: u64, id: u32, kind: &'static str, received: Instant, code: ErrorCode) {
+    record_request(kind, received.elapsed());
+    record_error(code);
+    let response = ServerMessage::Response(ServerResponse {
+        id,
+        data: ResponseData::Error(code),
+        ping: 0,
+    });
+    send_link(link, rmp_serde::to_vec(&response).unwrap());
+}
+
+/// Returns the session a request is from. Connecting without a valid token starts a new session, any other request needs the token of a session that is still open
+fn authenticate(token: Option<&SessionToken>, request: &ClientRequest) -> Result<u64, ErrorCode> {
+    let connecting = matches!(request, ClientRequest::Connect(_));
+    let uuid = match token.map(verify_token) {
+        Some(Some(uuid)) => uuid,
+        _ if connecting => return Ok(rand::random()),
+        Some(None) => return Err(ErrorCode::InvalidSession),
+        None => return Err(ErrorCode::NotConnected),
+    };
+    if !connecting && with_session(uuid, |_| ()).is_none() {
+        return Err(ErrorCode::NotConnected);
+    }
+    Ok(uuid)
+}
+
+/// Checks a `Connect` before it is routed. Every `Connect` takes from the link's bucket, and new sessions have to fit under [crate::config::ServerConfig::max_link_sessions] and [crate::config::ServerConfig::max_address_sessions]
+fn admit(link: u64, uuid: u64) -> Result<(), ErrorCode> {
+    if !allow_source(Source::Link(link), "Connect") {
+        return Err(ErrorCode::Ratelimited);
+    }
+    if with_session(uuid, |_| ()).is_some() {
+        return Ok(());
+    }
+
+    let (on_link, on_address) = session_counts(link);
+    if on_link >= config().max_link_sessions || on_address >= config().max_address_sessions {
+        return Err(ErrorCode::TooManySessions);
+    }
+    Ok(())
+}
+
+/// Handles a serialized [RequestEnvelope] from a link and responds to it. Errors if it can't be parsed, in which case the link's sessions should be closed
+pub fn handle_payload(link: u64, raw: &[u8]) -> Result<(), rmp_serde::decode::Error> {
+    let received = Instant::now();
+
+    // Requests from other protocol versions might not parse, so only check the header first
+    if let Some((version, id)) = RequestEnvelope::header(raw) {
+        if version != PROTOCOL_VERSION {
+            reject(link, id, "Unknown", received, ErrorCode::ProtocolMismatch);
+            return Ok(());
+        }
+    }
+
+    let envelope: RequestEnvelope = rmp_serde::from_slice(raw)?;
+    let kind = envelope.request.kind();
+
+    // The session answers from whichever link it last sent from, so it survives its address changing
+    let uuid = match authenticate(envelope.token.as_ref(), &envelope.request) {
+        Ok(uuid) => uuid,
+        Err(code) => {
+            reject(link, envelope.id, kind, received, code);
+            return Ok(());
+        }
+    };
+
+    // Connecting is free otherwise, so it counts as a strike when over its limits like ratelimited requests do
+    if let ClientRequest::Connect(_) = envelope.request {
+        if let Err(code) = admit(link, uuid) {
+            // Bans kick through the transports, so the lock can't be held while striking
+            if let Some(ip) = link_ip(link) {
+                strike(ip, Strike::Ratelimited);
+            }
+            reject(link, envelope.id, kind, received, code);
+            return Ok(());
+        }
+    }
+    route(uuid, link);
+    debug!(connection = uuid, link, kind, "Request");
+
+    let responder = Responder {
+        uuid,
+        id: envelope.id,
+        ping: now().saturating_sub(envelope.request.timestamp()),
+        kind,
+        received,
+    };
+    if let Some(data) = handle_request(&envelope.request, responder) {
+        responder.respond(data);
+    }
+    Ok(())
+}
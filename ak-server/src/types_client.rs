@@ -1,4 +1,28 @@
-use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use derive_new::new;
+use serde::de::{Error, IgnoredAny, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use uuid::Uuid;
+
+use crate::game::{GameMode, GameSettings};
+use crate::types_game::{BuildingKind, TilePos};
+
+/// Version of the protocol, bumped whenever requests or responses change in a way older clients can't parse
+pub const PROTOCOL_VERSION: u16 = 12;
+
+/// Longest chat message allowed, in bytes
+pub const MAX_CHAT_LENGTH: usize = 200;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Connect {
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Disconnect {
+    pub timestamp: u64,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Ping {
@@ -13,19 +37,236 @@ pub struct Rename {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateGame {
+    pub mode: GameMode,
+    /// Private games aren't listed and can only be joined with their code
+    pub private: bool,
+    /// Host the game as a spectator instead of a player, for games between AI players
+    pub spectate: bool,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JoinGame {
+    pub uuid: Uuid,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Spectate {
+    pub uuid: Uuid,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateProfile {
+    pub name: String,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Login {
+    pub id: Uuid,
+    /// From the [crate::types_server::ResponseData::ProfileCreated] the profile was created with
+    pub secret: String,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetProfile {
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reconnect {
+    pub game: Uuid,
+    /// From the last [crate::types_server::ReconnectToken] sent for the game
+    pub token: String,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JoinByCode {
+    pub code: String,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Kick {
+    /// Uuid of the player to kick
+    pub player: u64,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetSettings {
+    pub settings: GameSettings,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ready {
+    pub ready: bool,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddAi {
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StartGame {
+    pub timestamp: u64,
+}
+
+/// Who a chat message goes to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChatScope {
+    /// Everyone in the game
+    All,
+    /// Only players on the sender's team
+    Team,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub scope: ChatScope,
+    pub text: String,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LeaveGame {
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListGames {
+    pub timestamp: u64,
+}
+
+/// Sends one of the player's workers to a tile
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MoveWorker {
+    /// Id of one of the player's workers
+    pub worker: u32,
+    pub goal: TilePos,
+    pub timestamp: u64,
+}
+
+/// Has one of the player's workers mine an ore patch until told otherwise
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AssignOre {
+    /// Id of one of the player's workers
+    pub worker: u32,
+    /// Index of the ore patch in the map's ores
+    pub ore: usize,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlaceBuilding {
+    pub kind: BuildingKind,
+    /// Top-left tile of the building
+    pub pos: TilePos,
+    pub timestamp: u64,
+}
+
+/// Stops whatever one of the player's workers is doing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Cancel {
+    /// Id of one of the player's workers
+    pub worker: u32,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueueForMatch {
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LeaveQueue {
+    pub timestamp: u64,
+}
+
+/// Tells the game the newest snapshot the client has, so the next ones can be sent as deltas from it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AckSnapshot {
+    pub tick: u64,
+    pub timestamp: u64,
+}
+
+/// Hash of a lockstep client's game after a turn, see [crate::game::Game::checksum]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checksum {
+    pub turn: u64,
+    pub checksum: u64,
     pub timestamp: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ClientRequest {
+    /// Starts a session, must be sent before any other request. Answered with [crate::types_server::ResponseData::Connected], sending it again with the token resumes the session
+    Connect(Connect),
+    /// Ends the session
+    Disconnect(Disconnect),
+    /// Also acts as a heartbeat to keep the session alive
     Ping(Ping),
+    /// Renames the profile if logged in, names of profiles are unique
     Rename(Rename),
+    /// Creates a profile and logs into it, answered with [crate::types_server::ResponseData::ProfileCreated]
+    CreateProfile(CreateProfile),
+    /// Logs into a profile, answered with [crate::types_server::ResponseData::Profile]
+    Login(Login),
+    /// Gets the logged in profile with its latest stats
+    GetProfile(GetProfile),
+    /// Creates a game in its lobby with the sender as host
     CreateGame(CreateGame),
+    /// Joins a game as the next free [crate::types_game::Color]
+    JoinGame(JoinGame),
+    /// Joins a private game by its code, the code isn't case sensitive
+    JoinByCode(JoinByCode),
+    /// Watches a public game without taking a [crate::types_game::Color], spectators get every snapshot or turn but can't send commands
+    Spectate(Spectate),
+    /// Takes back the slot of a player whose connection dropped, answered like [ClientRequest::JoinGame]
+    Reconnect(Reconnect),
+    /// Removes a player from the game, host only
+    Kick(Kick),
+    /// Changes the game's settings before it starts and clears every ready-check, host only
+    SetSettings(SetSettings),
+    Ready(Ready),
+    /// Adds an AI player to the lobby, host only
+    AddAi(AddAi),
+    /// Starts the game once every player is ready, host only
+    StartGame(StartGame),
+    /// Sends a message to the game's chat, in the lobby or in game
+    ChatMessage(ChatMessage),
+    LeaveGame(LeaveGame),
+    ListGames(ListGames),
+    /// Waits to be put in a game with other queued players, answered with [crate::types_server::ResponseData::MatchFound] once it starts
+    QueueForMatch(QueueForMatch),
+    LeaveQueue(LeaveQueue),
+    MoveWorker(MoveWorker),
+    AssignOre(AssignOre),
+    PlaceBuilding(PlaceBuilding),
+    Cancel(Cancel),
+    /// Never answered, sent for every snapshot so it isn't ratelimited
+    AckSnapshot(AckSnapshot),
+    /// Never answered, a mismatch is sent to everyone in the game as [crate::types_server::ServerMessage::Desync]
+    Checksum(Checksum),
 }
 impl ClientRequest {
-    /// Returns true if the request should be rate limited
+    /// Returns true if the request should be rate limited per session. Requests sent every tick or needed to stay connected never are, `Connect` is ratelimited per link instead as it has no session yet
     pub fn ratelimited(&self) -> bool {
-        !matches!(self, ClientRequest::Ping(_))
+        !matches!(
+            self,
+            ClientRequest::Connect(_)
+                | ClientRequest::Disconnect(_)
+                | ClientRequest::Ping(_)
+                | ClientRequest::AckSnapshot(_)
+                | ClientRequest::Checksum(_)
+        )
     }
 
     /// Returns the timestamp of the request
@@ -41,6 +282,165 @@ impl ClientRequest {
             };
         }
 
-        timestamp!(Ping, Rename, CreateGame);
+        timestamp!(
+            Connect,
+            Disconnect,
+            Ping,
+            Rename,
+            CreateProfile,
+            Login,
+            GetProfile,
+            CreateGame,
+            JoinGame,
+            JoinByCode,
+            Spectate,
+            Reconnect,
+            Kick,
+            SetSettings,
+            Ready,
+            AddAi,
+            StartGame,
+            ChatMessage,
+            LeaveGame,
+            ListGames,
+            QueueForMatch,
+            LeaveQueue,
+            MoveWorker,
+            AssignOre,
+            PlaceBuilding,
+            Cancel,
+            AckSnapshot,
+            Checksum
+        );
+    }
+}
+
+/// Implements [ClientRequest::kind] and [ClientRequest::KINDS] from one list, so the two can't disagree
+macro_rules! request_kinds {
+    ($($x:ident),*) => {
+        impl ClientRequest {
+            /// Name of every variant, see [ClientRequest::kind]
+            pub const KINDS: &'static [&'static str] = &[$(stringify!($x)),*];
+
+            /// Returns the name of the request's variant, for logs, metrics and ratelimits
+            pub fn kind(&self) -> &'static str {
+                match self {
+                    $(ClientRequest::$x(_) => stringify!($x),)*
+                }
+            }
+        }
+    };
+}
+
+request_kinds!(
+    Connect,
+    Disconnect,
+    Ping,
+    Rename,
+    CreateProfile,
+    Login,
+    GetProfile,
+    CreateGame,
+    JoinGame,
+    JoinByCode,
+    Spectate,
+    Reconnect,
+    Kick,
+    SetSettings,
+    Ready,
+    AddAi,
+    StartGame,
+    ChatMessage,
+    LeaveGame,
+    ListGames,
+    QueueForMatch,
+    LeaveQueue,
+    MoveWorker,
+    AssignOre,
+    PlaceBuilding,
+    Cancel,
+    AckSnapshot,
+    Checksum
+);
+
+/// Wraps every [ClientRequest] sent to the server
+#[derive(new, Debug, Clone, Serialize, Deserialize)]
+pub struct RequestEnvelope {
+    /// Protocol version of the client, has to match [PROTOCOL_VERSION]
+    #[new(value = "PROTOCOL_VERSION")]
+    pub version: u16,
+    /// Chosen by the client and echoed back in the [crate::types_server::ServerResponse]
+    pub id: u32,
+    /// Session the request is from, every request other than [ClientRequest::Connect] needs one
+    #[new(value = "None")]
+    pub token: Option<SessionToken>,
+    pub request: ClientRequest,
+}
+impl RequestEnvelope {
+    /// Reads only the `(version, id)` of a serialized envelope, so requests from clients on another protocol version can still be answered. The rest of the envelope is skipped, however many fields it has
+    pub fn header(raw: &[u8]) -> Option<(u16, u32)> {
+        struct Header(u16, u32);
+        impl<'de> Deserialize<'de> for Header {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                struct HeaderVisitor;
+                impl<'de> Visitor<'de> for HeaderVisitor {
+                    type Value = Header;
+
+                    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                        f.write_str("a request envelope")
+                    }
+
+                    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Header, A::Error> {
+                        let version = seq
+                            .next_element()?
+                            .ok_or_else(|| A::Error::invalid_length(0, &self))?;
+                        let id = seq
+                            .next_element()?
+                            .ok_or_else(|| A::Error::invalid_length(1, &self))?;
+                        while seq.next_element::<IgnoredAny>()?.is_some() {}
+                        Ok(Header(version, id))
+                    }
+                }
+
+                deserializer.deserialize_seq(HeaderVisitor)
+            }
+        }
+
+        rmp_serde::from_slice::<Header>(raw)
+            .ok()
+            .map(|Header(version, id)| (version, id))
+    }
+}
+
+/// Proves which session a request is from. Signed by the server so it can't be forged, and unlike the client's address it stays the same when the address changes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionToken {
+    pub session: u64,
+    #[serde(with = "serde_bytes")]
+    pub signature: Vec<u8>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header() {
+        let envelope = RequestEnvelope::new(7, ClientRequest::Ping(Ping { timestamp: 0 }));
+        let raw = rmp_serde::to_vec(&envelope).unwrap();
+        assert_eq!(RequestEnvelope::header(&raw), Some((PROTOCOL_VERSION, 7)));
+    }
+
+    #[test]
+    fn header_of_another_version() {
+        // An old client with fewer fields and a request this version doesn't know
+        let raw = rmp_serde::to_vec(&(PROTOCOL_VERSION - 1, 7u32, ("Removed", [1, 2]))).unwrap();
+        assert!(rmp_serde::from_slice::<RequestEnvelope>(&raw).is_err());
+        assert_eq!(
+            RequestEnvelope::header(&raw),
+            Some((PROTOCOL_VERSION - 1, 7))
+        );
+
+        assert_eq!(RequestEnvelope::header(&[]), None);
     }
 }
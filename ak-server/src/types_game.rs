@@ -1,13 +1,28 @@
 use derive_new::new;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rustc_hash::{FxHashMap, FxHashSet};
 use serde::{Deserialize, Serialize};
 
-#[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize)]
+use crate::hashmap;
+
+/// Size of a tile in pixels, same as the client's `SQUARE_SIZE`
+pub const TILE_SIZE: f32 = 32.0;
+
+/// Position of a tile on a [ServerMap]
+pub type TilePos = (u32, u32);
+
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum Color {
     Blue,
     Red,
     Green,
     Yellow,
 }
+impl Color {
+    /// Every color, in the order they are given to players
+    pub const ALL: [Color; 4] = [Color::Blue, Color::Red, Color::Green, Color::Yellow];
+}
 
 #[rustfmt::skip]
 #[derive(Deserialize, Serialize, Clone, Copy, Debug, Hash, PartialEq, Eq)]
@@ -35,10 +50,24 @@ impl Texture {
     pub fn as_server(&self) -> Sprite {
         Sprite::Sprite(*self)
     }
+
+    /// Returns the spritesheet of a worker of the given color, walking or idle, facing the given direction
+    pub fn worker(color: Color, walking: bool, direction: Direction) -> Texture {
+        use Texture::*;
+
+        #[rustfmt::skip]
+        let textures = match color {
+            Color::Blue => [BlueWorkerIdleUp, BlueWorkerIdleDown, BlueWorkerIdleLeft, BlueWorkerIdleRight, BlueWorkerWalkUp, BlueWorkerWalkDown, BlueWorkerWalkLeft, BlueWorkerWalkRight],
+            Color::Red => [RedWorkerIdleUp, RedWorkerIdleDown, RedWorkerIdleLeft, RedWorkerIdleRight, RedWorkerWalkUp, RedWorkerWalkDown, RedWorkerWalkLeft, RedWorkerWalkRight],
+            Color::Green => [GreenWorkerIdleUp, GreenWorkerIdleDown, GreenWorkerIdleLeft, GreenWorkerIdleRight, GreenWorkerWalkUp, GreenWorkerWalkDown, GreenWorkerWalkLeft, GreenWorkerWalkRight],
+            Color::Yellow => [YellowWorkerIdleUp, YellowWorkerIdleDown, YellowWorkerIdleLeft, YellowWorkerIdleRight, YellowWorkerWalkUp, YellowWorkerWalkDown, YellowWorkerWalkLeft, YellowWorkerWalkRight],
+        };
+        textures[walking as usize * 4 + direction as usize]
+    }
 }
 
 /// A texture or a spritesheet, used to transmit textures to the client and server
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Sprite {
     Sprite(Texture),
     SpriteSheet {
@@ -51,10 +80,155 @@ pub enum Sprite {
     },
 }
 
+#[derive(Hash, Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Direction {
+    Up,
+    #[default]
+    Down,
+    Left,
+    Right,
+}
+
+#[derive(Hash, Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum OreKind {
+    Gold,
+}
+impl OreKind {
+    /// Size of a patch of the ore in tiles, same as its texture
+    pub fn size(&self) -> (u32, u32) {
+        match self {
+            OreKind::Gold => (4, 4),
+        }
+    }
+
+    /// Time between each piece of ore a worker mines, in seconds
+    pub fn cooldown(&self) -> f32 {
+        match self {
+            OreKind::Gold => 0.5,
+        }
+    }
+}
+
+/// A patch of ore on the map that workers can mine
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerOrePatch {
+    /// Top-left tile of the patch
+    pub pos: TilePos,
+    pub kind: OreKind,
+    pub max: u32,
+    pub remaining: u32,
+}
+impl ServerOrePatch {
+    pub fn new(pos: TilePos, kind: OreKind, max: u32) -> ServerOrePatch {
+        ServerOrePatch {
+            pos,
+            kind,
+            max,
+            remaining: max,
+        }
+    }
+
+    /// Every tile the patch covers
+    pub fn tiles(&self) -> impl Iterator<Item = TilePos> {
+        footprint(self.pos, self.kind.size())
+    }
+}
+
+#[derive(Hash, Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum BuildingKind {
+    House,
+}
+impl BuildingKind {
+    /// Size of the building in tiles, same as its texture
+    pub fn size(&self) -> (u32, u32) {
+        match self {
+            BuildingKind::House => (4, 4),
+        }
+    }
+
+    /// Ore it takes to place the building, same as the client's `Building::cost`
+    pub fn cost(&self) -> &'static [(OreKind, u32)] {
+        match self {
+            BuildingKind::House => &[(OreKind::Gold, 10)],
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ServerBuilding {
+    pub kind: BuildingKind,
+    /// Top-left tile of the building
+    pub pos: TilePos,
+}
+impl ServerBuilding {
+    /// Every tile the building covers
+    pub fn tiles(&self) -> impl Iterator<Item = TilePos> {
+        footprint(self.pos, self.kind.size())
+    }
+}
+
+/// Every tile of a `size` area with `pos` as the top-left
+pub fn footprint(pos: TilePos, size: (u32, u32)) -> impl Iterator<Item = TilePos> {
+    (pos.1..pos.1 + size.1).flat_map(move |y| (pos.0..pos.0 + size.0).map(move |x| (x, y)))
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct ServerWorker {
+    /// Unique in its game and never reused, so workers can be told apart across snapshots
+    pub id: u32,
+    /// Top-left of the worker in world coordinates
     pub pos: (f32, f32),
     pub sprite: Sprite,
+    /// Whether the worker is mining its ore
+    pub mining: bool,
+
+    /// World positions left to walk through, never in snapshots but lockstep clients simulate it
+    pub path: Option<Vec<(f32, f32)>>,
+    /// Index of the ore patch the worker is mining in the [ServerMap]'s ores
+    pub ore: Option<usize>,
+    pub direction: Direction,
+    /// Seconds until the worker can mine again
+    pub mine_cooldown: f32,
+    /// Seconds the worker has been animating, used to pick the spritesheet frame
+    pub anim_time: f32,
+}
+impl ServerWorker {
+    /// Pixels a worker walks per second
+    pub const SPEED: f32 = 200.0;
+
+    pub fn new(id: u32, color: Color, pos: (f32, f32)) -> ServerWorker {
+        ServerWorker {
+            id,
+            pos,
+            sprite: Sprite::SpriteSheet {
+                texture: Texture::worker(color, false, Direction::Down),
+                frames: 1,
+                current_frame: 0,
+            },
+            mining: false,
+            path: None,
+            ore: None,
+            direction: Direction::Down,
+            mine_cooldown: 0.0,
+            anim_time: 0.0,
+        }
+    }
+
+    /// The tile the worker's top-left is on
+    pub fn tile(&self) -> TilePos {
+        (
+            (self.pos.0 / TILE_SIZE) as u32,
+            (self.pos.1 / TILE_SIZE) as u32,
+        )
+    }
+
+    /// Every tile the worker overlaps, up to 4 when it is between tiles
+    pub fn tiles(&self) -> impl Iterator<Item = TilePos> {
+        let (left, top) = self.tile();
+        let right = ((self.pos.0 + TILE_SIZE - 1.0) / TILE_SIZE) as u32;
+        let bottom = ((self.pos.1 + TILE_SIZE - 1.0) / TILE_SIZE) as u32;
+        footprint((left, top), (right - left + 1, bottom - top + 1))
+    }
 }
 
 #[derive(new, Clone, Serialize, Deserialize)]
@@ -65,6 +239,20 @@ pub struct ServerPlayer {
     #[new(value = "vec![]")]
     pub workers: Vec<ServerWorker>,
     pub color: Color,
+    /// How much of each ore the player has
+    #[new(value = "hashmap! {}")]
+    pub ores: FxHashMap<OreKind, u32>,
+    #[new(value = "vec![]")]
+    pub buildings: Vec<ServerBuilding>,
+    /// Controlled by the server, used to fill matches that don't have enough people
+    #[new(value = "false")]
+    pub ai: bool,
+    /// Players on the same team see each other's team chat. Every player starts on a team of their own
+    #[new(value = "0")]
+    pub team: u8,
+    /// Ore mined over the whole game, spending ore doesn't lower it
+    #[new(value = "0")]
+    pub gathered: u64,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
@@ -78,13 +266,69 @@ pub struct ServerMap {
     pub tiles: Vec<Vec<Tile>>,
     pub width: usize,
     pub height: usize,
+    pub ores: Vec<ServerOrePatch>,
 }
 impl ServerMap {
-    pub fn random() -> ServerMap {
-        ServerMap {
-            tiles: vec![],
-            width: 0,
-            height: 0,
+    /// Ore patches placed by the seed, on top of the one every map has
+    const EXTRA_ORES: usize = 3;
+
+    /// Generates a map from a seed, the same seed always gives the same map. Every map is the client's walled test room with a gold patch in the top left, the seed places the other ore patches away from where players spawn
+    pub fn generate(seed: u64) -> ServerMap {
+        let (width, height) = (150, 46);
+        let tiles = (0..height)
+            .map(|y| {
+                (0..width)
+                    .map(|x| {
+                        if x == 0 || y == 0 || x == width - 1 || y == height - 1 {
+                            Tile::Wall
+                        } else {
+                            Tile::Air
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let mut map = ServerMap {
+            tiles,
+            width,
+            height,
+            ores: vec![ServerOrePatch::new((5, 5), OreKind::Gold, 1000)],
+        };
+
+        // Spawns are in the corners, so patches stay in the middle columns. Patches keep a tile between each other so there is always a way around
+        let mut rng = StdRng::seed_from_u64(seed);
+        let (ore_width, ore_height) = OreKind::Gold.size();
+        let mut attempts = 0;
+        while map.ores.len() < 1 + Self::EXTRA_ORES && attempts < 100 {
+            attempts += 1;
+            let pos = (
+                rng.gen_range(15..width as u32 - 15 - ore_width),
+                rng.gen_range(2..height as u32 - 2 - ore_height),
+            );
+            let taken: FxHashSet<TilePos> = map
+                .ores
+                .iter()
+                .flat_map(|ore| {
+                    footprint(
+                        (ore.pos.0 - 1, ore.pos.1 - 1),
+                        (ore_width + 2, ore_height + 2),
+                    )
+                })
+                .collect();
+            if footprint(pos, (ore_width, ore_height)).all(|tile| !taken.contains(&tile)) {
+                map.ores.push(ServerOrePatch::new(pos, OreKind::Gold, 1000));
+            }
         }
+        map
+    }
+
+    /// Returns a tile at a given position, positions outside the map are walls
+    pub fn get(&self, pos: TilePos) -> Tile {
+        self.tiles
+            .get(pos.1 as usize)
+            .and_then(|row| row.get(pos.0 as usize))
+            .copied()
+            .unwrap_or(Tile::Wall)
     }
 }
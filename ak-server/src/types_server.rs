@@ -1,23 +1,513 @@
+use rustc_hash::{FxHashMap, FxHashSet};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::game::{Game, GameSettings, Turn};
+use crate::types_client::{ChatScope, SessionToken};
+use crate::types_game::{Color, ServerMap, ServerOrePatch, ServerPlayer, ServerWorker, Sprite};
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum ErrorCode {
     /// The username contains invalid characters
     UsernameInvalid,
     /// The username is too long
     UsernameTooLong,
+    /// Another profile already has the name
+    NameTaken,
+    /// The profile doesn't exist or the secret is wrong
+    ProfileNotFound,
+    /// Profiles couldn't be read or saved, nothing was changed
+    ProfileUnavailable,
+    /// The session is already logged into a profile
+    AlreadyLoggedIn,
+    NotLoggedIn,
+    /// The reconnect token isn't for a player in that game, or the grace period ran out
+    InvalidToken,
+    /// The session token wasn't signed by this server, it might have restarted since. Connect again for a new one
+    InvalidSession,
+    /// The chat message is empty or contains invalid characters
+    MessageInvalid,
+    /// The chat message is longer than [crate::types_client::MAX_CHAT_LENGTH]
+    MessageTooLong,
     /// Sent request too fast
     Ratelimited,
     AlreadyInGame,
+    /// Already waiting for a match, see [crate::types_client::ClientRequest::QueueForMatch]
+    AlreadyQueued,
+    NotQueued,
+    /// Only the host of a game can do that
+    NotHost,
+    /// The game is still in its lobby
+    NotStarted,
+    /// The game already left its lobby
+    AlreadyStarted,
+    /// Not every player in the lobby is ready
+    NotReady,
+    PlayerNotFound,
+    /// The settings are out of range, see [crate::game::GameSettings::valid]
+    InvalidSettings,
+    /// Sent a request without a session, see [crate::types_client::ClientRequest::Connect]
+    NotConnected,
+    /// The client uses a different [crate::types_client::PROTOCOL_VERSION] than the server
+    ProtocolMismatch,
+    /// The server already has as many connections as it allows
+    ServerFull,
+    /// The link or address already has as many sessions as the server allows from one place
+    TooManySessions,
+    /// The server already has as many games as it allows
+    TooManyGames,
+    /// The game already has [crate::game::MAX_PLAYERS] players
+    GameFull,
+    /// The game already has as many spectators as it allows
+    SpectatorsFull,
+    /// Spectators can't do that, only players can
+    Spectating,
+    GameNotFound,
+    NotInGame,
+    /// The player has no worker with that id
+    WorkerNotFound,
+    OreNotFound,
+    /// The worker has no path to where it was sent
+    Unreachable,
+    /// Something is in the way of the building, or it doesn't fit on the map
+    BuildingBlocked,
+    /// The player doesn't have enough ore for the building
+    CannotAfford,
 }
 
+/// What is shown about a game in the game list
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct GameSummary {
+    pub uuid: Uuid,
+    pub players: u8,
+    /// Whether the game left its lobby
+    pub started: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ResponseData {
     Error(ErrorCode),
-    GameCreateSuccess(Uuid),
+    /// Private games also get a code for friends to join with, see [crate::types_client::ClientRequest::JoinByCode]
+    GameCreateSuccess {
+        uuid: Uuid,
+        code: Option<String>,
+    },
+    /// Joined the game as this color
+    GameJoinSuccess(Color),
+    GameList(Vec<GameSummary>),
+    /// Answers [crate::types_client::ClientRequest::QueueForMatch] once a match starts, the player is already in the game
+    MatchFound {
+        uuid: Uuid,
+        color: Color,
+    },
+    /// Answers [crate::types_client::ClientRequest::Connect], the token goes in every request after
+    Connected(SessionToken),
+    /// Answers [crate::types_client::ClientRequest::CreateProfile], the secret is needed to log in again and is never sent again
+    ProfileCreated {
+        profile: ProfileInfo,
+        secret: String,
+    },
+    Profile(ProfileInfo),
     Success,
 }
 
-/// `(response, ping)`
-pub type ServerResponse = (ResponseData, u16);
+/// Stats of a profile, added to whenever a started game is left
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct ProfileStats {
+    pub games_played: u32,
+    /// Games where every other person left while the player stayed, there is no other way to win yet
+    pub wins: u32,
+    /// Ore mined over every game
+    pub gathered: u64,
+}
+
+/// What is shown about a profile
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProfileInfo {
+    pub id: Uuid,
+    pub name: String,
+    /// When the profile was created, in milliseconds
+    pub created: u64,
+    pub stats: ProfileStats,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerResponse {
+    /// Id of the [crate::types_client::RequestEnvelope] this responds to
+    pub id: u32,
+    pub data: ResponseData,
+    /// How long the request took to reach the server in milliseconds
+    pub ping: u16,
+}
+
+/// The state of a game after a tick. Players are sent a [SnapshotDelta] from one they already have instead of the whole thing
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Tick the snapshot was taken on, older snapshots arriving late can be ignored
+    pub tick: u64,
+    pub players: Vec<ServerPlayer>,
+    pub ores: Vec<ServerOrePatch>,
+}
+impl Snapshot {
+    /// Returns what changed since `baseline`, or everything if there is no baseline
+    pub fn delta(&self, baseline: Option<&Snapshot>) -> SnapshotDelta {
+        let mut delta = SnapshotDelta {
+            tick: self.tick,
+            baseline: baseline.map(|baseline| baseline.tick),
+            players: vec![],
+            removed_players: vec![],
+            workers: vec![],
+            removed_workers: vec![],
+            ores: None,
+        };
+
+        let old_players = baseline.map_or(&[][..], |baseline| &baseline.players[..]);
+        let old_workers: FxHashMap<u32, &ServerWorker> = old_players
+            .iter()
+            .flat_map(|player| player.workers.iter())
+            .map(|worker| (worker.id, worker))
+            .collect();
+
+        for player in self.players.iter() {
+            let old = old_players.iter().find(|old| old.uuid == player.uuid);
+            let changed = old.is_none_or(|old| {
+                old.ping != player.ping
+                    || old.color != player.color
+                    || old.ores != player.ores
+                    || old.buildings != player.buildings
+            });
+            if changed {
+                delta.players.push(ServerPlayer {
+                    workers: vec![],
+                    ..player.clone()
+                });
+            }
+
+            for worker in player.workers.iter() {
+                let old = old_workers.get(&worker.id);
+                let worker_delta = WorkerDelta {
+                    id: worker.id,
+                    owner: player.uuid,
+                    pos: (old.map(|old| old.pos) != Some(worker.pos)).then_some(worker.pos),
+                    sprite: (old.map(|old| old.sprite) != Some(worker.sprite))
+                        .then_some(worker.sprite),
+                    mining: (old.map(|old| old.mining) != Some(worker.mining))
+                        .then_some(worker.mining),
+                };
+                if worker_delta.pos.is_some()
+                    || worker_delta.sprite.is_some()
+                    || worker_delta.mining.is_some()
+                {
+                    delta.workers.push(worker_delta);
+                }
+            }
+        }
+
+        let workers: FxHashSet<u32> = self
+            .players
+            .iter()
+            .flat_map(|player| player.workers.iter())
+            .map(|worker| worker.id)
+            .collect();
+        delta.removed_workers = old_workers
+            .keys()
+            .filter(|id| !workers.contains(id))
+            .copied()
+            .collect();
+        delta.removed_players = old_players
+            .iter()
+            .filter(|old| self.players.iter().all(|player| player.uuid != old.uuid))
+            .map(|old| old.uuid)
+            .collect();
+
+        if baseline.is_none_or(|baseline| baseline.ores != self.ores) {
+            delta.ores = Some(self.ores.clone());
+        }
+        delta
+    }
+}
+
+/// The changes between a snapshot the client has and the newest one
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SnapshotDelta {
+    pub tick: u64,
+    /// Tick of the snapshot this is relative to, `None` if it has everything
+    pub baseline: Option<u64>,
+    /// Players that are new or whose anything other than workers changed, sent without their workers
+    pub players: Vec<ServerPlayer>,
+    pub removed_players: Vec<u64>,
+    /// Workers that are new or changed
+    pub workers: Vec<WorkerDelta>,
+    pub removed_workers: Vec<u32>,
+    /// Every ore patch, only sent if any of them changed
+    pub ores: Option<Vec<ServerOrePatch>>,
+}
+impl SnapshotDelta {
+    /// Rebuilds the full snapshot from the one the delta is relative to. Returns `None` if the baseline is needed but the wrong one was given
+    pub fn apply(&self, baseline: Option<&Snapshot>) -> Option<Snapshot> {
+        let mut snapshot = match (self.baseline, baseline) {
+            (None, _) => Snapshot {
+                tick: self.tick,
+                players: vec![],
+                ores: vec![],
+            },
+            (Some(tick), Some(baseline)) if baseline.tick == tick => baseline.clone(),
+            _ => return None,
+        };
+        snapshot.tick = self.tick;
+
+        snapshot
+            .players
+            .retain(|player| !self.removed_players.contains(&player.uuid));
+        for player in snapshot.players.iter_mut() {
+            player
+                .workers
+                .retain(|worker| !self.removed_workers.contains(&worker.id));
+        }
+
+        for changed in self.players.iter() {
+            match snapshot
+                .players
+                .iter_mut()
+                .find(|player| player.uuid == changed.uuid)
+            {
+                Some(player) => {
+                    let workers = std::mem::take(&mut player.workers);
+                    *player = ServerPlayer {
+                        workers,
+                        ..changed.clone()
+                    };
+                }
+                None => snapshot.players.push(changed.clone()),
+            }
+        }
+
+        for changed in self.workers.iter() {
+            let player = snapshot
+                .players
+                .iter_mut()
+                .find(|player| player.uuid == changed.owner)?;
+            let worker = match player
+                .workers
+                .iter_mut()
+                .find(|worker| worker.id == changed.id)
+            {
+                Some(worker) => worker,
+                None => {
+                    let color = player.color;
+                    player.workers.push(ServerWorker::new(
+                        changed.id,
+                        color,
+                        changed.pos.unwrap_or_default(),
+                    ));
+                    player.workers.last_mut().unwrap()
+                }
+            };
+
+            if let Some(pos) = changed.pos {
+                worker.pos = pos;
+            }
+            if let Some(sprite) = changed.sprite {
+                worker.sprite = sprite;
+            }
+            if let Some(mining) = changed.mining {
+                worker.mining = mining;
+            }
+        }
+
+        if let Some(ores) = &self.ores {
+            snapshot.ores = ores.clone();
+        }
+        Some(snapshot)
+    }
+}
+
+/// The parts of a worker that changed, new workers have every part
+#[derive(Clone, Serialize, Deserialize)]
+pub struct WorkerDelta {
+    pub id: u32,
+    /// Uuid of the player the worker belongs to
+    pub owner: u64,
+    pub pos: Option<(f32, f32)>,
+    pub sprite: Option<Sprite>,
+    pub mining: Option<bool>,
+}
+
+/// Everything the server sends to clients
+#[derive(Clone, Serialize, Deserialize)]
+pub enum ServerMessage {
+    Response(ServerResponse),
+    /// Sent unreliably, deltas are against the last snapshot the client acked so a lost one doesn't break the next
+    Snapshot(SnapshotDelta),
+    /// The map of a game that sends snapshots, sent on joining. Snapshots only have what changes on it
+    Map(ServerMap),
+    /// The state of a lockstep game to simulate from, sent on joining before any [ServerMessage::Turn]
+    LockstepStart(LockstepStart),
+    /// Sent reliably every turn of a lockstep game
+    Turn(Turn),
+    Desync(Desync),
+    /// Sent to everyone in a game whenever its lobby or host changes
+    Lobby(LobbyState),
+    /// The host kicked the player from the game
+    Kicked,
+    Chat(Chat),
+    /// A message from the server's operator to everyone connected
+    Announcement(String),
+    /// The server ended the session, with the reason. Sent when kicked or banned by the operator, or when the server shuts down
+    Closed(String),
+    /// Sent to a player when they join or reconnect, see [crate::types_client::ClientRequest::Reconnect]
+    ReconnectToken(ReconnectToken),
+}
+
+/// Lets a new connection take back a player's slot after their connection dropped. Only the newest token a player was sent works
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReconnectToken {
+    pub game: Uuid,
+    pub token: String,
+}
+
+/// A chat message from a player in the game
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Chat {
+    pub from: u64,
+    pub username: String,
+    pub scope: ChatScope,
+    pub text: String,
+}
+
+/// Who is in a game and who runs it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LobbyState {
+    /// Uuid of the player that can kick players, change settings and start the game. Games from matchmaking have no host
+    pub host: Option<u64>,
+    /// Join code of a private game
+    pub code: Option<String>,
+    pub started: bool,
+    pub settings: GameSettings,
+    pub players: Vec<LobbyPlayer>,
+    pub spectators: Vec<LobbySpectator>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LobbyPlayer {
+    pub uuid: u64,
+    pub username: String,
+    pub color: Color,
+    /// Whether the player sent the ready-check, see [crate::types_client::ClientRequest::Ready]
+    pub ready: bool,
+    pub ai: bool,
+    /// `false` while the player's connection dropped and their slot is kept for them to reconnect
+    pub connected: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LobbySpectator {
+    pub uuid: u64,
+    pub username: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LockstepStart {
+    pub game: Game,
+    /// Length of a tick in seconds, passed to [Game::apply_turn]
+    pub dt: f32,
+}
+
+/// A lockstep client's checksum didn't match the server's
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Desync {
+    pub turn: u64,
+    /// Uuid of the player that desynced
+    pub player: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types_game::OreKind;
+
+    /// Asserts two snapshots are the same in everything snapshots carry, workers' paths and the like never are
+    fn assert_same(left: &Snapshot, right: &Snapshot) {
+        assert_eq!(left.tick, right.tick);
+        assert_eq!(left.ores, right.ores);
+        assert_eq!(left.players.len(), right.players.len());
+        for player in left.players.iter() {
+            let other = right
+                .players
+                .iter()
+                .find(|other| other.uuid == player.uuid)
+                .unwrap();
+            assert_eq!(player.ping, other.ping);
+            assert_eq!(player.color, other.color);
+            assert_eq!(player.ores, other.ores);
+            assert_eq!(player.buildings, other.buildings);
+
+            assert_eq!(player.workers.len(), other.workers.len());
+            for worker in player.workers.iter() {
+                let other = other
+                    .workers
+                    .iter()
+                    .find(|other| other.id == worker.id)
+                    .unwrap();
+                assert_eq!(worker.pos, other.pos);
+                assert_eq!(worker.sprite, other.sprite);
+                assert_eq!(worker.mining, other.mining);
+            }
+        }
+    }
+
+    fn game() -> Game {
+        let mut game = Game::new();
+        game.add_player(1);
+        game.add_player(2);
+        game.add_player(3);
+        game
+    }
+
+    #[test]
+    fn full_snapshot() {
+        let snapshot = game().snapshot();
+        let delta = snapshot.delta(None);
+        assert_same(&delta.apply(None).unwrap(), &snapshot);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut game = game();
+        let base = game.snapshot();
+
+        game.tick += 1;
+        // Changed, added and removed workers
+        let first = &mut game.players[0];
+        first.workers[0].pos.0 += 10.0;
+        first.workers[0].mining = true;
+        first.workers.pop();
+        first.ores.insert(OreKind::Gold, 5);
+        let (id, color) = (game.next_worker_id, game.players[1].color);
+        game.next_worker_id += 1;
+        game.players[1]
+            .workers
+            .push(ServerWorker::new(id, color, (0.0, 0.0)));
+        // Removed and added players
+        game.players.remove(2);
+        game.add_player(4);
+        let next = game.snapshot();
+
+        let delta = next.delta(Some(&base));
+        assert_same(&delta.apply(Some(&base)).unwrap(), &next);
+    }
+
+    #[test]
+    fn stale_baseline() {
+        let mut game = game();
+        let stale = game.snapshot();
+        game.tick += 1;
+        let base = game.snapshot();
+        game.tick += 1;
+        let next = game.snapshot();
+
+        let delta = next.delta(Some(&base));
+        assert!(delta.apply(Some(&stale)).is_none());
+        assert!(delta.apply(None).is_none());
+    }
+}
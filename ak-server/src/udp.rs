@@ -0,0 +1,173 @@
+//! Links to every address sending to the server, adding reliability and fragmentation on top of the raw UDP socket
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use ak_server::fragment::{Fragmenter, ReassemblyLimits};
+use ak_server::hashmap;
+use ak_server::reliable::{Channel, Packet};
+use ak_server::util::now;
+use lazy_static::lazy_static;
+use rustc_hash::FxHashMap;
+use tokio::net::UdpSocket;
+use tracing::error;
+
+use crate::config::config;
+use crate::metrics::traffic;
+use crate::session::{close_link, SESSION_TIMEOUT};
+use crate::transport::{is_routed, register, unregister, Transport, TransportKind};
+
+/// How often unacked packets are checked for resending
+const RESEND_INTERVAL: Duration = Duration::from_millis(50);
+
+static SOCKET: OnceLock<UdpSocket> = OnceLock::new();
+
+/// The reliability and fragmentation state for a single address
+struct Link {
+    addr: SocketAddr,
+    channel: Channel,
+    fragmenter: Fragmenter,
+    /// Last time a packet was received from the address
+    last_seen: u64,
+    /// Whether a session has been routed through the link, until then it only gets [ReassemblyLimits::SESSIONLESS]
+    trusted: bool,
+}
+impl Link {
+    fn new(addr: SocketAddr) -> Link {
+        let mut fragmenter = Fragmenter::new(config().max_datagram);
+        fragmenter.set_limits(ReassemblyLimits::SESSIONLESS);
+        Link {
+            addr,
+            channel: Channel::new(),
+            fragmenter,
+            last_seen: now(),
+            trusted: false,
+        }
+    }
+
+    /// Sends a packet, split into as many datagrams as needed
+    fn send_packet(&mut self, packet: &Packet) {
+        let raw = rmp_serde::to_vec(packet).unwrap();
+        let datagrams = match self.fragmenter.split(&raw) {
+            Some(datagrams) => datagrams,
+            None => {
+                error!(bytes = raw.len(), "Packet too big to send");
+                return;
+            }
+        };
+
+        for datagram in datagrams {
+            // A full socket buffer only drops the datagram, reliable packets get resent
+            if socket().try_send_to(&datagram, self.addr).is_ok() {
+                traffic(TransportKind::Udp).sent(datagram.len());
+            }
+        }
+    }
+}
+
+lazy_static! {
+    /// Map of every address sending packets to its link
+    static ref LINKS: Mutex<FxHashMap<u64, Link>> = Mutex::from(hashmap! {});
+}
+
+/// Binds the server socket, can only be called once
+pub async fn bind(addr: SocketAddr) -> io::Result<&'static UdpSocket> {
+    let bound = UdpSocket::bind(addr).await?;
+    if SOCKET.set(bound).is_err() {
+        panic!("Socket already bound");
+    }
+    Ok(socket())
+}
+
+/// Returns the server socket, panics if [bind] wasn't called
+pub fn socket() -> &'static UdpSocket {
+    SOCKET.get().expect("Socket not bound")
+}
+
+/// Handles a datagram from an address, acking it if needed. Returns the payloads that are ready to be handled, in order. Datagrams from new addresses are dropped once there are [crate::config::ServerConfig::max_links] links, or [crate::config::ServerConfig::max_address_links] from the same IP
+pub fn receive(
+    uuid: u64,
+    addr: SocketAddr,
+    raw: &[u8],
+) -> Result<Vec<Vec<u8>>, rmp_serde::decode::Error> {
+    let mut links = LINKS.lock().unwrap();
+    if !links.contains_key(&uuid) {
+        let from_address = links
+            .values()
+            .filter(|link| link.addr.ip() == addr.ip())
+            .count();
+        if links.len() >= config().max_links || from_address >= config().max_address_links {
+            return Ok(vec![]);
+        }
+        register(uuid, Transport::Udp, addr.ip());
+        links.insert(uuid, Link::new(addr));
+    }
+    let link = links.get_mut(&uuid).unwrap();
+    link.last_seen = now();
+
+    // A session vouches for the link, so it can send messages as big as any other
+    if !link.trusted && is_routed(uuid) {
+        link.trusted = true;
+        link.fragmenter.set_limits(ReassemblyLimits::FULL);
+    }
+
+    let raw = match link.fragmenter.join(raw, now())? {
+        Some(raw) => raw,
+        None => return Ok(vec![]),
+    };
+    let packet: Packet = rmp_serde::from_slice(&raw)?;
+
+    let (ready, ack) = link.channel.receive(packet);
+    if let Some(ack) = ack {
+        link.send_packet(&ack);
+    }
+    Ok(ready)
+}
+
+/// Sends a payload over the reliable channel, does nothing if the address never sent anything. Use [crate::transport::send] instead unless the connection is known to be over UDP
+pub fn send(uuid: u64, payload: Vec<u8>) {
+    if let Some(link) = LINKS.lock().unwrap().get_mut(&uuid) {
+        let packet = link.channel.send_reliable(payload, now());
+        link.send_packet(&packet);
+    }
+}
+
+/// Sends a payload without resending it if lost, does nothing if the address never sent anything
+pub fn send_unreliable(uuid: u64, payload: Vec<u8>) {
+    if let Some(link) = LINKS.lock().unwrap().get_mut(&uuid) {
+        let packet = link.channel.send_unreliable(payload);
+        link.send_packet(&packet);
+    }
+}
+
+/// Resends unacked packets and forgets addresses that haven't sent anything in [SESSION_TIMEOUT] along with the sessions still routed through them, runs forever
+pub async fn maintain_links() {
+    let mut interval = tokio::time::interval(RESEND_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let now = now();
+        let mut links = LINKS.lock().unwrap();
+        let mut expired = vec![];
+        links.retain(|uuid, link| {
+            let alive = link.last_seen + SESSION_TIMEOUT >= now;
+            if !alive {
+                expired.push(*uuid);
+            }
+            alive
+        });
+        for link in links.values_mut() {
+            for packet in link.channel.resend(now) {
+                link.send_packet(&packet);
+            }
+        }
+        drop(links);
+
+        for uuid in expired {
+            unregister(uuid);
+            close_link(uuid);
+        }
+    }
+}
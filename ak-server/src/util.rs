@@ -1,3 +1,10 @@
+use chrono::Utc;
+
+/// Returns the current unix timestamp in milliseconds
+pub fn now() -> u64 {
+    Utc::now().timestamp_millis() as u64
+}
+
 /// Create [rustc_hash::FxHashMap]'s using a readable syntax, similar to dicts in python or objects in js. Adapted from maplit to support `FxHashMap`
 ///
 /// ## Example
@@ -0,0 +1,81 @@
+//! WebSocket listener, so clients that can't open UDP sockets, like the wasm build, can still connect. WebSockets are already reliable and ordered, so requests and responses are sent as plain binary messages
+
+use std::io;
+use std::net::SocketAddr;
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::unbounded_channel;
+use tokio_tungstenite::accept_async;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{info, warn};
+
+use crate::bans::{is_banned, strike, Strike};
+use crate::metrics::traffic;
+use crate::session::close_link;
+use crate::transport::{handle_payload, hash_addr, register, unregister, Transport, TransportKind};
+
+/// Accepts WebSocket connections forever, handling each one in its own task
+pub async fn listen(addr: SocketAddr) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!(addr = %listener.local_addr()?, "Listening for WebSockets");
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        tokio::spawn(handle_connection(stream, addr));
+    }
+}
+
+async fn handle_connection(stream: TcpStream, addr: SocketAddr) {
+    if is_banned(addr.ip()) {
+        return;
+    }
+
+    let socket = match accept_async(stream).await {
+        Ok(socket) => socket,
+        Err(err) => {
+            warn!(%addr, ?err, "WebSocket handshake failed");
+            return;
+        }
+    };
+    let link = hash_addr(TransportKind::WebSocket, addr);
+    let (mut write, mut read) = socket.split();
+
+    // Everything sent to the connection goes through this channel, so it can be sent from anywhere
+    let (sender, mut receiver) = unbounded_channel::<Vec<u8>>();
+    register(link, Transport::WebSocket(sender), addr.ip());
+    let writer = tokio::spawn(async move {
+        while let Some(payload) = receiver.recv().await {
+            let len = payload.len();
+            if write.send(Message::Binary(payload)).await.is_err() {
+                break;
+            }
+            traffic(TransportKind::WebSocket).sent(len);
+        }
+    });
+
+    while let Some(Ok(message)) = read.next().await {
+        // Banned while connected
+        if is_banned(addr.ip()) {
+            break;
+        }
+
+        match message {
+            Message::Binary(raw) => {
+                traffic(TransportKind::WebSocket).received(raw.len());
+                if let Err(err) = handle_payload(link, &raw) {
+                    warn!(link, ?err, "Failed to deserialize request");
+                    strike(addr.ip(), Strike::Malformed);
+                    break;
+                }
+            }
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+
+    // Unlike UDP there is no need to wait for a timeout, the socket is gone
+    unregister(link);
+    close_link(link);
+    writer.abort();
+}
@@ -1,13 +1,21 @@
 use ak_server::types_game::{Color, Texture};
 use derive_new::new;
+use macroquad::time::get_frame_time;
 
 use crate::conf::SILVER_FONT;
 use crate::map::Map;
+use crate::net::Connection;
 use crate::objects::camera::Camera;
 use crate::objects::player::Player;
 use crate::objects::worker::workers_iter_mut;
 use crate::texture_map::load_texture;
 
+/// Length of a simulation step in seconds, workers are always updated by this much so the simulation doesn't depend on the frame rate
+pub(crate) const STEP: f32 = 1.0 / 60.0;
+
+/// Most steps simulated in a frame, so a long frame doesn't snowball into longer ones
+const MAX_STEPS: u32 = 8;
+
 static mut GAME: Option<Game> = None;
 /// Returns the global [Game] object as a mutable reference
 pub(crate) fn game() -> &'static mut Game {
@@ -32,6 +40,18 @@ pub(crate) struct Game {
 
     #[new(value = "Map::new()")]
     pub(crate) map: Map,
+
+    /// Connection to the server, polled every frame
+    #[new(value = "Connection::new()")]
+    pub(crate) net: Connection,
+
+    /// Seconds simulated so far, advanced by [STEP]
+    #[new(value = "0.0")]
+    pub(crate) time: f64,
+
+    /// Frame time not yet simulated
+    #[new(value = "0.0")]
+    accumulator: f32,
 }
 
 impl Game {
@@ -69,21 +89,33 @@ impl Game {
 
     pub(crate) fn init(&mut self) {
         self.map.set_camera_bounds();
+        self.net.connect();
     }
 
     pub(crate) fn update(&mut self) {
         self.map.update();
         self.players[self.main_player].update();
         self.camera.update();
-        for worker in workers_iter_mut() {
-            worker.update();
+
+        self.accumulator = (self.accumulator + get_frame_time()).min(STEP * MAX_STEPS as f32);
+        while self.accumulator >= STEP {
+            self.accumulator -= STEP;
+            self.time += STEP as f64;
+            if !self.net.in_game() {
+                for worker in workers_iter_mut() {
+                    worker.update(STEP);
+                }
+            }
+            self.net.update(STEP);
         }
     }
 
     pub(crate) fn draw(&mut self) {
         self.map.draw();
+        self.net.draw_workers();
         self.players[self.main_player].draw();
         self.map.draw_minimap();
+        self.net.draw_status();
     }
 
     pub(crate) fn player(&self, color: Color) -> &Player {
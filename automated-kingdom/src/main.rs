@@ -14,6 +14,7 @@ pub(crate) mod geometry;
 pub(crate) mod map;
 pub(crate) mod map_gen;
 pub(crate) mod math;
+pub(crate) mod net;
 pub(crate) mod objects;
 pub(crate) mod spritesheet;
 pub(crate) mod texture_map;
@@ -73,6 +74,7 @@ async fn main() {
     game().init();
 
     loop {
+        game().net.poll();
         game().update();
         game().draw();
         next_frame().await;
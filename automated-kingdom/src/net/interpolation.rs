@@ -0,0 +1,125 @@
+//! Remote workers are drawn a little in the past, blended between the two snapshots around that time, so they move smoothly instead of jumping every tick
+
+use std::collections::VecDeque;
+
+use ak_server::types_game::{Color, Sprite};
+use ak_server::types_server::Snapshot;
+
+use crate::conf::SQUARE_SIZE;
+use crate::geometry::CollisionRect;
+
+/// How far in the past remote workers are drawn, in seconds. Long enough that the next snapshot has usually arrived, even if one is lost
+const INTERPOLATION_DELAY: f64 = 0.1;
+
+/// Most snapshots kept, far more than [INTERPOLATION_DELAY] needs at any tick rate
+const BUFFER_LEN: usize = 32;
+
+/// A worker as it should be drawn this frame
+#[derive(Clone)]
+pub(crate) struct RenderWorker {
+    pub(crate) id: u32,
+    pub(crate) color: Color,
+    pub(crate) pos: (f32, f32),
+    pub(crate) sprite: Sprite,
+}
+impl RenderWorker {
+    /// Where the worker is drawn, the same size as local workers
+    pub(crate) fn rect(&self) -> CollisionRect {
+        CollisionRect::new(self.pos.0, self.pos.1, SQUARE_SIZE, SQUARE_SIZE)
+    }
+}
+
+/// Snapshots along with when they were received, oldest first
+pub(crate) struct InterpolationBuffer {
+    snapshots: VecDeque<(f64, Snapshot)>,
+}
+impl InterpolationBuffer {
+    pub(crate) fn new() -> InterpolationBuffer {
+        InterpolationBuffer {
+            snapshots: VecDeque::new(),
+        }
+    }
+
+    /// Adds a snapshot received at `time`, must be newer than every snapshot already in the buffer
+    pub(crate) fn push(&mut self, time: f64, snapshot: Snapshot) {
+        if self.snapshots.len() >= BUFFER_LEN {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back((time, snapshot));
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.snapshots.clear();
+    }
+
+    /// Returns every worker as it was [INTERPOLATION_DELAY] before `now`. Nothing is extrapolated, if the buffer runs dry workers stay where the newest snapshot has them
+    pub(crate) fn sample(&self, now: f64) -> Vec<RenderWorker> {
+        let render_time = now - INTERPOLATION_DELAY;
+
+        let next = self
+            .snapshots
+            .iter()
+            .position(|(time, _)| *time > render_time);
+        let (from, to, t) = match next {
+            None => match self.snapshots.back() {
+                Some((_, newest)) => (newest, newest, 1.0),
+                None => return vec![],
+            },
+            Some(0) => (&self.snapshots[0].1, &self.snapshots[0].1, 1.0),
+            Some(i) => {
+                let (from_time, from) = &self.snapshots[i - 1];
+                let (to_time, to) = &self.snapshots[i];
+                let t = (render_time - from_time) / (to_time - from_time);
+                (from, to, t as f32)
+            }
+        };
+
+        let mut workers = vec![];
+        for player in to.players.iter() {
+            let old_player = from.players.iter().find(|old| old.uuid == player.uuid);
+            for worker in player.workers.iter() {
+                let old = old_player
+                    .and_then(|old| old.workers.iter().find(|old| old.id == worker.id))
+                    .unwrap_or(worker);
+                workers.push(RenderWorker {
+                    id: worker.id,
+                    color: player.color,
+                    pos: (
+                        old.pos.0 + (worker.pos.0 - old.pos.0) * t,
+                        old.pos.1 + (worker.pos.1 - old.pos.1) * t,
+                    ),
+                    sprite: blend_sprite(old.sprite, worker.sprite, t),
+                });
+            }
+        }
+        workers
+    }
+}
+
+/// Blends between two sprites. The same spritesheet plays through the frames in between, anything else switches halfway
+fn blend_sprite(from: Sprite, to: Sprite, t: f32) -> Sprite {
+    match (from, to) {
+        (
+            Sprite::SpriteSheet {
+                texture: from_texture,
+                frames: from_frames,
+                current_frame: from_frame,
+            },
+            Sprite::SpriteSheet {
+                texture,
+                frames,
+                current_frame,
+            },
+        ) if from_texture == texture && from_frames == frames => {
+            // Animations only go forward, so wrapping around is the short way
+            let ahead = (current_frame + frames - from_frame) % frames;
+            Sprite::SpriteSheet {
+                texture,
+                frames,
+                current_frame: (from_frame + (ahead as f32 * t).round() as u16) % frames,
+            }
+        }
+        _ if t < 0.5 => from,
+        _ => to,
+    }
+}
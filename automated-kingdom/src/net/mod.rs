@@ -0,0 +1,562 @@
+//! Connection to the server. Requests are queued and sent when the connection is polled, once per frame from the main loop, and responses are dispatched to the handlers they were sent with
+
+use std::collections::VecDeque;
+
+use ak_server::game::{Game as ServerGame, Turn};
+use ak_server::types_client::{
+    AckSnapshot, ChatScope, Checksum, ClientRequest, Connect, Disconnect, MoveWorker, Ping,
+    Reconnect, RequestEnvelope, SessionToken,
+};
+use ak_server::types_game::{ServerMap, ServerPlayer, TilePos};
+use ak_server::types_server::{
+    Chat, Desync, ErrorCode, LobbyState, LockstepStart, ReconnectToken, ResponseData,
+    ServerMessage, ServerResponse, Snapshot, SnapshotDelta,
+};
+use macroquad::color_u8;
+use macroquad::miniquad::date;
+use macroquad::prelude::{is_mouse_button_pressed, Color, MouseButton, WHITE};
+use macroquad::text::measure_text;
+use macroquad::time::get_time;
+use macroquad::window::screen_width;
+use rustc_hash::FxHashMap;
+
+#[cfg(not(target_family = "wasm"))]
+use self::udp::Socket;
+#[cfg(target_family = "wasm")]
+use self::ws::Socket;
+use crate::conf::SILVER_FONT;
+use crate::game::game;
+use crate::hashmap;
+use crate::map::Map;
+use crate::net::interpolation::{InterpolationBuffer, RenderWorker};
+use crate::net::prediction::Prediction;
+use crate::texture_map::DrawSprite;
+use crate::util::{draw_rel_text_top_left, screen_mouse_pos};
+
+mod interpolation;
+mod prediction;
+#[cfg(not(target_family = "wasm"))]
+mod udp;
+#[cfg(target_family = "wasm")]
+mod ws;
+
+/// Address of the server, wasm has to go through the WebSocket listener
+#[cfg(not(target_family = "wasm"))]
+const SERVER_ADDRESS: &str = "127.0.0.1:8080";
+#[cfg(target_family = "wasm")]
+const SERVER_ADDRESS: &str = "ws://127.0.0.1:8090";
+
+/// How often a ping is sent to keep the session alive and measure the ping, in seconds
+const HEARTBEAT_INTERVAL: f64 = 1.0;
+
+/// How long the server can go without responding before the connection is considered lost, in seconds
+const TIMEOUT: f64 = 10.0;
+
+/// How often a lockstep game's checksum is sent to the server, in turns
+const CHECKSUM_INTERVAL: u64 = 10;
+
+/// How many chat messages are kept, the oldest is dropped to make room
+const CHAT_HISTORY: usize = 50;
+
+/// How many past snapshots are kept for deltas to be applied to, the server may send deltas from any snapshot acked before the newest
+const SNAPSHOT_HISTORY: usize = 32;
+
+/// Returns the current unix timestamp in milliseconds
+pub(crate) fn timestamp() -> u64 {
+    (date::now() * 1000.0) as u64
+}
+
+/// Called with the response to a request
+pub(crate) type Handler = Box<dyn FnOnce(ResponseData)>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ConnectionState {
+    Disconnected,
+    /// Waiting for the server to respond to [ClientRequest::Connect]
+    Connecting,
+    Connected,
+}
+
+pub(crate) struct Connection {
+    socket: Option<Socket>,
+
+    pub(crate) state: ConnectionState,
+
+    /// Round trip time of the last heartbeat in milliseconds
+    pub(crate) ping: Option<u16>,
+
+    /// Newest state of the game the server sent, if in one
+    pub(crate) snapshot: Option<Snapshot>,
+
+    /// Recent snapshots, oldest first, for deltas from a snapshot older than [Self::snapshot]
+    history: VecDeque<Snapshot>,
+
+    /// Map of the game the snapshots are from, used to predict paths
+    map: Option<ServerMap>,
+
+    /// Snapshots remote workers are drawn from
+    interpolation: InterpolationBuffer,
+
+    /// The player's own workers that were commanded and are moving ahead of the snapshots
+    prediction: Prediction,
+
+    /// The game simulated from the server's turns, if in a lockstep game, and the length of its ticks
+    pub(crate) lockstep: Option<(ServerGame, f32)>,
+
+    /// Set once the server reports the lockstep game went out of sync
+    pub(crate) desync: Option<Desync>,
+
+    /// Players, settings and host of the game, if in one
+    pub(crate) lobby: Option<LobbyState>,
+
+    /// Recent chat messages of the game, oldest first
+    pub(crate) chat: VecDeque<Chat>,
+
+    /// Token for the game the player is in, kept when the connection drops so the next connection can take the player's slot back
+    reconnect: Option<ReconnectToken>,
+
+    /// Id of the next request
+    next_id: u32,
+
+    /// Requests waiting to be sent on the next [Self::poll]
+    queue: Vec<RequestEnvelope>,
+
+    /// Handlers for requests that haven't been responded to yet
+    handlers: FxHashMap<u32, Handler>,
+
+    /// Id of the [ClientRequest::Connect] request, if not yet responded to
+    connect_id: Option<u32>,
+
+    /// Id of the player's worker selected in the game the snapshots are from
+    selected_worker: Option<u32>,
+
+    /// Token of the session, sent with every request. Kept when the connection drops so the next connection picks the same session back up
+    session: Option<SessionToken>,
+
+    /// Heartbeats that haven't been responded to yet, and when they were sent
+    heartbeats: FxHashMap<u32, f64>,
+
+    last_heartbeat: f64,
+    last_response: f64,
+}
+impl Connection {
+    pub(crate) fn new() -> Connection {
+        Connection {
+            socket: None,
+            state: ConnectionState::Disconnected,
+            ping: None,
+            snapshot: None,
+            history: VecDeque::new(),
+            map: None,
+            interpolation: InterpolationBuffer::new(),
+            prediction: Prediction::new(),
+            lockstep: None,
+            desync: None,
+            lobby: None,
+            chat: VecDeque::new(),
+            reconnect: None,
+            next_id: 0,
+            queue: vec![],
+            handlers: hashmap! {},
+            connect_id: None,
+            selected_worker: None,
+            session: None,
+            heartbeats: hashmap! {},
+            last_heartbeat: 0.0,
+            last_response: 0.0,
+        }
+    }
+
+    /// Opens a connection to the server and starts a session, does nothing if already connected or connecting
+    pub(crate) fn connect(&mut self) {
+        if self.state != ConnectionState::Disconnected {
+            return;
+        }
+
+        self.socket = match Socket::open(SERVER_ADDRESS) {
+            Ok(socket) => Some(socket),
+            Err(_) => return,
+        };
+        self.state = ConnectionState::Connecting;
+        self.last_heartbeat = get_time();
+        self.last_response = get_time();
+
+        let id = self.queue_request(ClientRequest::Connect(Connect {
+            timestamp: timestamp(),
+        }));
+        self.connect_id = Some(id);
+    }
+
+    /// Ends the session and closes the connection
+    pub(crate) fn disconnect(&mut self) {
+        if let Some(socket) = &mut self.socket {
+            let mut envelope = RequestEnvelope::new(
+                self.next_id,
+                ClientRequest::Disconnect(Disconnect {
+                    timestamp: timestamp(),
+                }),
+            );
+            envelope.token = self.session.clone();
+            socket.send(rmp_serde::to_vec(&envelope).unwrap());
+        }
+        self.session = None;
+        self.reconnect = None;
+        self.close();
+    }
+
+    /// Drops the connection without telling the server, along with every queued request and handler
+    fn close(&mut self) {
+        self.socket = None;
+        self.state = ConnectionState::Disconnected;
+        self.ping = None;
+        self.leave_game();
+        self.queue.clear();
+        self.handlers.clear();
+        self.connect_id = None;
+        self.heartbeats.clear();
+    }
+
+    fn queue_request(&mut self, request: ClientRequest) -> u32 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let mut envelope = RequestEnvelope::new(id, request);
+        envelope.token = self.session.clone();
+        self.queue.push(envelope);
+        id
+    }
+
+    /// Queues a request to be sent on the next [Self::poll], ignoring the response. Dropped if disconnected
+    pub(crate) fn send(&mut self, request: ClientRequest) {
+        if self.state != ConnectionState::Disconnected {
+            self.queue_request(request);
+        }
+    }
+
+    /// Queues a request to be sent on the next [Self::poll], calling `handler` with the response. If disconnected `handler` is called right away with [ErrorCode::NotConnected]
+    pub(crate) fn send_with(
+        &mut self,
+        request: ClientRequest,
+        handler: impl FnOnce(ResponseData) + 'static,
+    ) {
+        if self.state == ConnectionState::Disconnected {
+            handler(ResponseData::Error(ErrorCode::NotConnected));
+            return;
+        }
+
+        let id = self.queue_request(request);
+        self.handlers.insert(id, Box::new(handler));
+    }
+
+    /// Sends queued requests and heartbeats, and dispatches every response received since the last poll. Call once per frame
+    pub(crate) fn poll(&mut self) {
+        if self.socket.is_none() {
+            return;
+        }
+
+        if get_time() - self.last_heartbeat >= HEARTBEAT_INTERVAL {
+            self.last_heartbeat = get_time();
+            let id = self.queue_request(ClientRequest::Ping(Ping {
+                timestamp: timestamp(),
+            }));
+            self.heartbeats.insert(id, get_time());
+        }
+
+        let socket = self.socket.as_mut().unwrap();
+        for envelope in self.queue.drain(..) {
+            socket.send(rmp_serde::to_vec(&envelope).unwrap());
+        }
+
+        for raw in socket.receive() {
+            let message = match rmp_serde::from_slice::<ServerMessage>(&raw) {
+                Ok(message) => message,
+                Err(_) => continue,
+            };
+            self.last_response = get_time();
+
+            match message {
+                ServerMessage::Response(response) => self.handle_response(response),
+                ServerMessage::Snapshot(delta) => self.handle_snapshot(delta),
+                ServerMessage::Map(map) => self.map = Some(map),
+                ServerMessage::LockstepStart(LockstepStart { game, dt }) => {
+                    self.lockstep = Some((game, dt));
+                    self.desync = None;
+                }
+                ServerMessage::Turn(turn) => self.handle_turn(turn),
+                ServerMessage::Desync(desync) => self.desync = Some(desync),
+                ServerMessage::Lobby(lobby) => self.lobby = Some(lobby),
+                ServerMessage::Kicked => {
+                    self.reconnect = None;
+                    self.leave_game();
+                }
+                ServerMessage::ReconnectToken(token) => self.reconnect = Some(token),
+                ServerMessage::Announcement(text) => self.push_chat(Chat {
+                    from: 0,
+                    username: String::from("Server"),
+                    scope: ChatScope::All,
+                    text,
+                }),
+                ServerMessage::Closed(_) => {
+                    self.reconnect = None;
+                    self.close();
+                    return;
+                }
+                ServerMessage::Chat(chat) => self.push_chat(chat),
+            }
+        }
+
+        if get_time() - self.last_response > TIMEOUT {
+            self.close();
+        }
+    }
+
+    fn push_chat(&mut self, chat: Chat) {
+        if self.chat.len() >= CHAT_HISTORY {
+            self.chat.pop_front();
+        }
+        self.chat.push_back(chat);
+    }
+
+    /// Forgets everything about the game the connection was in
+    fn leave_game(&mut self) {
+        self.snapshot = None;
+        self.history.clear();
+        self.map = None;
+        self.interpolation.clear();
+        self.prediction.clear();
+        self.selected_worker = None;
+        self.lockstep = None;
+        self.desync = None;
+        self.lobby = None;
+        self.chat.clear();
+    }
+
+    /// Takes back the player's slot in the game they were in before the connection dropped, if they were in one
+    fn rejoin(&mut self) {
+        let Some(token) = self.reconnect.clone() else {
+            return;
+        };
+        let request = ClientRequest::Reconnect(Reconnect {
+            game: token.game,
+            token: token.token,
+            timestamp: timestamp(),
+        });
+        self.send_with(request, |data| {
+            // The game ended or the slot is gone, trying again won't help
+            if let ResponseData::Error(_) = data {
+                game().net.reconnect = None;
+            }
+        });
+    }
+
+    fn handle_response(&mut self, response: ServerResponse) {
+        if let ResponseData::Error(ErrorCode::ProtocolMismatch) = response.data {
+            self.close();
+            return;
+        }
+
+        if self.connect_id == Some(response.id) {
+            self.connect_id = None;
+            match &response.data {
+                ResponseData::Connected(token) => {
+                    self.session = Some(token.clone());
+                    self.state = ConnectionState::Connected;
+                    self.rejoin();
+                }
+                _ => {
+                    self.close();
+                    return;
+                }
+            }
+        }
+
+        if let Some(sent) = self.heartbeats.remove(&response.id) {
+            self.ping = Some(((get_time() - sent) * 1000.0) as u16);
+        }
+
+        if let Some(handler) = self.handlers.remove(&response.id) {
+            handler(response.data);
+        }
+    }
+
+    /// Rebuilds the snapshot from the delta and acks it, keeping it if it is newer than the current one. Over UDP they can arrive out of order, and deltas from a snapshot that is no longer kept are dropped
+    fn handle_snapshot(&mut self, delta: SnapshotDelta) {
+        if self
+            .snapshot
+            .as_ref()
+            .is_some_and(|current| current.tick >= delta.tick)
+        {
+            return;
+        }
+
+        let baseline = delta
+            .baseline
+            .and_then(|tick| self.history.iter().find(|snapshot| snapshot.tick == tick));
+        let Some(snapshot) = delta.apply(baseline) else {
+            return;
+        };
+
+        self.send(ClientRequest::AckSnapshot(AckSnapshot {
+            tick: snapshot.tick,
+            timestamp: timestamp(),
+        }));
+        if self.history.len() >= SNAPSHOT_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(snapshot.clone());
+        self.prediction.correct(&snapshot);
+        self.interpolation.push(get_time(), snapshot.clone());
+        self.snapshot = Some(snapshot);
+    }
+
+    /// Uuid of the player in games, the same as the id of its session
+    fn uuid(&self) -> Option<u64> {
+        self.session.as_ref().map(|token| token.session)
+    }
+
+    /// Whether the player is in a game on the server, in which case the player's workers are the server's instead of the local ones
+    pub(crate) fn in_game(&self) -> bool {
+        self.snapshot.is_some() || self.lockstep.is_some()
+    }
+
+    /// Players of the game the player is in, from the simulated game in lockstep games and the latest snapshot otherwise
+    fn players(&self) -> &[ServerPlayer] {
+        match (&self.lockstep, &self.snapshot) {
+            (Some((game, _)), _) => &game.players,
+            (None, Some(snapshot)) => &snapshot.players,
+            (None, None) => &[],
+        }
+    }
+
+    /// Whether the worker belongs to the player
+    fn owns(&self, worker: u32) -> bool {
+        let Some(uuid) = self.uuid() else {
+            return false;
+        };
+        self.players()
+            .iter()
+            .filter(|player| player.uuid == uuid)
+            .any(|player| player.workers.iter().any(|current| current.id == worker))
+    }
+
+    /// Workers as they should be drawn this frame. Lockstep games are drawn as simulated, snapshots are predicted where they are being predicted and interpolated everywhere else
+    fn render_workers(&self) -> Vec<RenderWorker> {
+        if let Some((game, _)) = &self.lockstep {
+            return game
+                .players
+                .iter()
+                .flat_map(|player| {
+                    player.workers.iter().map(|worker| RenderWorker {
+                        id: worker.id,
+                        color: player.color,
+                        pos: worker.pos,
+                        sprite: worker.sprite,
+                    })
+                })
+                .collect();
+        }
+
+        self.interpolation
+            .sample(get_time())
+            .into_iter()
+            .map(|worker| self.prediction.get(worker.id).unwrap_or(worker))
+            .collect()
+    }
+
+    /// Selects the player's worker under the mouse on a left click, and sends the selected one to the tile under the mouse on a right click
+    pub(crate) fn update_workers(&mut self) {
+        if is_mouse_button_pressed(MouseButton::Left) {
+            let clicked = self.render_workers().into_iter().find(|worker| {
+                self.owns(worker.id) && worker.rect().touches_point(&screen_mouse_pos())
+            });
+            if let Some(worker) = clicked {
+                self.selected_worker =
+                    (self.selected_worker != Some(worker.id)).then_some(worker.id);
+            }
+        }
+
+        if is_mouse_button_pressed(MouseButton::Right) {
+            if let Some(worker) = self.selected_worker.filter(|worker| self.owns(*worker)) {
+                let pos = Map::world_to_pos(screen_mouse_pos());
+                self.move_worker(worker, (pos.x, pos.y));
+            }
+        }
+    }
+
+    /// Sends one of the player's workers to a tile, moving it right away until the server answers. The prediction is dropped if the server turns the command down
+    pub(crate) fn move_worker(&mut self, worker: u32, goal: TilePos) {
+        if let (Some(snapshot), Some(map), Some(uuid)) = (&self.snapshot, &self.map, self.uuid()) {
+            self.prediction
+                .move_worker(snapshot, map, uuid, worker, goal);
+        }
+
+        let request = ClientRequest::MoveWorker(MoveWorker {
+            worker,
+            goal,
+            timestamp: timestamp(),
+        });
+        self.send_with(request, move |data| {
+            if let ResponseData::Error(_) = data {
+                game().net.prediction.cancel(worker);
+            }
+        });
+    }
+
+    /// Moves predicted workers by `dt` seconds
+    pub(crate) fn update(&mut self, dt: f32) {
+        self.prediction.update(dt);
+    }
+
+    /// Draws the workers of the game the player is in, see [Self::render_workers]
+    pub(crate) fn draw_workers(&self) {
+        for worker in self.render_workers() {
+            worker.sprite.draw(worker.pos.0, worker.pos.1);
+            if self.selected_worker == Some(worker.id) {
+                worker.rect().draw_lines(2.5, color_u8!(255, 255, 255, 200));
+            }
+        }
+    }
+
+    /// Simulates a lockstep turn, sending the checksum every [CHECKSUM_INTERVAL] turns. Turns arrive reliably and in order, so one that doesn't follow the last is from before the game started
+    fn handle_turn(&mut self, turn: Turn) {
+        let Some((game, dt)) = &mut self.lockstep else {
+            return;
+        };
+        if turn.turn != game.turn() + 1 {
+            return;
+        }
+
+        game.apply_turn(&turn, *dt);
+        if turn.turn % CHECKSUM_INTERVAL == 0 {
+            let checksum = game.checksum();
+            self.send(ClientRequest::Checksum(Checksum {
+                turn: turn.turn,
+                checksum,
+                timestamp: timestamp(),
+            }));
+        }
+    }
+
+    /// Draws the connection state and ping to the top right of the screen
+    pub(crate) fn draw_status(&self) {
+        let text = match (self.state, self.ping) {
+            (ConnectionState::Connected, _) if self.desync.is_some() => {
+                format!("Desynced on turn {}", self.desync.unwrap().turn)
+            }
+            (ConnectionState::Connected, Some(ping)) => format!("Ping: {ping}ms"),
+            (ConnectionState::Connected, None) => "Connected".to_string(),
+            (ConnectionState::Connecting, _) => "Connecting...".to_string(),
+            (ConnectionState::Disconnected, _) => "Offline".to_string(),
+        };
+
+        let font_size = 32;
+        let margin = 8.0;
+        let measurements = measure_text(&text, Some(*SILVER_FONT), font_size, 1.0);
+        draw_rel_text_top_left(
+            &text,
+            screen_width() - measurements.width - margin,
+            margin,
+            font_size as f32,
+            WHITE,
+        );
+    }
+}
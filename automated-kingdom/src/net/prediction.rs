@@ -0,0 +1,155 @@
+//! The local player's workers start moving as soon as they are commanded instead of a round trip later. The server still decides where they are, a prediction too far from the server's snapshot is snapped back to it
+
+use ak_server::astar::astar;
+use ak_server::game::{update_path, update_sprite};
+use ak_server::types_game::{Color, ServerMap, ServerWorker, TilePos, TILE_SIZE};
+use ak_server::types_server::Snapshot;
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::hashmap;
+use crate::net::interpolation::RenderWorker;
+
+/// How far a prediction can be from the server's position before it is snapped back, in pixels. Predictions run a little ahead of snapshots, so small differences are expected
+const CORRECTION_DISTANCE: f32 = TILE_SIZE * 2.0;
+
+/// Snapshots in a row the server has to have a worker standing still before an arrived prediction is dropped, so the interpolated worker has stopped too
+const SETTLE_SNAPSHOTS: u32 = 4;
+
+/// A worker moving on its own ahead of the server
+struct Predicted {
+    worker: ServerWorker,
+    color: Color,
+    /// Where the server had the worker in the last snapshot
+    server_pos: Option<(f32, f32)>,
+    /// Snapshots in a row the server's position hasn't changed
+    still: u32,
+}
+
+pub(crate) struct Prediction {
+    workers: FxHashMap<u32, Predicted>,
+}
+impl Prediction {
+    pub(crate) fn new() -> Prediction {
+        Prediction {
+            workers: hashmap! {},
+        }
+    }
+
+    /// Starts moving one of `owner`'s workers to `goal` with the same pathfinding as the server. Returns `false` if the owner doesn't have the worker or a path couldn't be found, nothing is predicted then
+    pub(crate) fn move_worker(
+        &mut self,
+        snapshot: &Snapshot,
+        map: &ServerMap,
+        owner: u64,
+        worker: u32,
+        goal: TilePos,
+    ) -> bool {
+        let Some((color, current)) = snapshot
+            .players
+            .iter()
+            .filter(|player| player.uuid == owner)
+            .find_map(|player| {
+                player
+                    .workers
+                    .iter()
+                    .find(|current| current.id == worker)
+                    .map(|current| (player.color, current))
+            })
+        else {
+            return false;
+        };
+
+        // A worker already being predicted keeps going from where it is drawn
+        let mut predicted = match self.workers.remove(&worker) {
+            Some(predicted) => predicted.worker,
+            None => current.clone(),
+        };
+
+        let blocked: FxHashSet<TilePos> = snapshot
+            .ores
+            .iter()
+            .flat_map(|ore| ore.tiles())
+            .chain(
+                snapshot
+                    .players
+                    .iter()
+                    .flat_map(|player| player.buildings.iter())
+                    .flat_map(|building| building.tiles()),
+            )
+            .collect();
+        let Some(path) = astar(predicted.tile(), goal, map, &blocked) else {
+            return false;
+        };
+
+        predicted.path = Some(path);
+        self.workers.insert(
+            worker,
+            Predicted {
+                worker: predicted,
+                color,
+                server_pos: None,
+                still: 0,
+            },
+        );
+        true
+    }
+
+    /// Stops predicting a worker, for commands the server turned down
+    pub(crate) fn cancel(&mut self, worker: u32) {
+        self.workers.remove(&worker);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.workers.clear();
+    }
+
+    /// Moves every predicted worker by `dt` seconds
+    pub(crate) fn update(&mut self, dt: f32) {
+        for predicted in self.workers.values_mut() {
+            let moved = update_path(&mut predicted.worker, dt);
+            update_sprite(&mut predicted.worker, predicted.color, moved, dt);
+        }
+    }
+
+    /// Checks predictions against a new snapshot. Workers that are gone are dropped, workers too far from the server's position are snapped to it, and workers that arrived are handed back to the snapshots once the server has them stopped
+    pub(crate) fn correct(&mut self, snapshot: &Snapshot) {
+        self.workers.retain(|id, predicted| {
+            let Some(server) = snapshot
+                .players
+                .iter()
+                .flat_map(|player| player.workers.iter())
+                .find(|server| server.id == *id)
+            else {
+                return false;
+            };
+
+            let (dx, dy) = (
+                server.pos.0 - predicted.worker.pos.0,
+                server.pos.1 - predicted.worker.pos.1,
+            );
+            let distance = (dx * dx + dy * dy).sqrt();
+            if distance > CORRECTION_DISTANCE {
+                predicted.worker.pos = server.pos;
+            }
+
+            if predicted.server_pos == Some(server.pos) {
+                predicted.still += 1;
+            } else {
+                predicted.still = 0;
+            }
+            predicted.server_pos = Some(server.pos);
+
+            predicted.worker.path.is_some() || predicted.still < SETTLE_SNAPSHOTS
+        });
+    }
+
+    /// Returns the predicted worker with this id, if it is being predicted
+    pub(crate) fn get(&self, id: u32) -> Option<RenderWorker> {
+        self.workers.get(&id).map(|predicted| RenderWorker {
+            id,
+            color: predicted.color,
+            pos: predicted.worker.pos,
+            sprite: predicted.worker.sprite,
+        })
+    }
+}
@@ -0,0 +1,80 @@
+//! UDP socket used on native, with the same reliability and fragmentation as the server
+
+use std::io::{self, ErrorKind};
+use std::net::UdpSocket;
+
+use ak_server::fragment::{Fragmenter, DEFAULT_MAX_DATAGRAM, MAX_UDP_PAYLOAD};
+use ak_server::reliable::{Channel, Packet};
+
+use crate::net::timestamp;
+
+pub(crate) struct Socket {
+    socket: UdpSocket,
+    channel: Channel,
+    fragmenter: Fragmenter,
+    buf: Vec<u8>,
+}
+impl Socket {
+    /// Opens a non-blocking socket to the server
+    pub(crate) fn open(addr: &str) -> io::Result<Socket> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(addr)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Socket {
+            socket,
+            channel: Channel::new(),
+            fragmenter: Fragmenter::new(DEFAULT_MAX_DATAGRAM),
+            buf: vec![0; MAX_UDP_PAYLOAD],
+        })
+    }
+
+    fn send_packet(&mut self, packet: &Packet) {
+        let raw = rmp_serde::to_vec(packet).unwrap();
+        if let Some(datagrams) = self.fragmenter.split(&raw) {
+            for datagram in datagrams {
+                // Lost datagrams are fine, reliable packets get resent
+                let _ = self.socket.send(&datagram);
+            }
+        }
+    }
+
+    /// Sends a payload over the reliable channel
+    pub(crate) fn send(&mut self, payload: Vec<u8>) {
+        let packet = self.channel.send_reliable(payload, timestamp());
+        self.send_packet(&packet);
+    }
+
+    /// Returns every payload received since the last call, resending lost packets along the way
+    pub(crate) fn receive(&mut self) -> Vec<Vec<u8>> {
+        let mut payloads = vec![];
+        loop {
+            let n = match self.socket.recv(&mut self.buf) {
+                Ok(n) => n,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                // Errors like the server not listening yet, just try again next frame
+                Err(_) => break,
+            };
+
+            let raw = match self.fragmenter.join(&self.buf[..n], timestamp()) {
+                Ok(Some(raw)) => raw,
+                _ => continue,
+            };
+            let packet: Packet = match rmp_serde::from_slice(&raw) {
+                Ok(packet) => packet,
+                Err(_) => continue,
+            };
+
+            let (ready, ack) = self.channel.receive(packet);
+            if let Some(ack) = ack {
+                self.send_packet(&ack);
+            }
+            payloads.extend(ready);
+        }
+
+        for packet in self.channel.resend(timestamp()) {
+            self.send_packet(&packet);
+        }
+        payloads
+    }
+}
@@ -0,0 +1,71 @@
+//! WebSocket used on wasm, since browsers can't open UDP sockets. WebSockets are already reliable and ordered, so payloads are sent as is
+
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+
+use js_sys::{ArrayBuffer, Uint8Array};
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::JsCast;
+use web_sys::{BinaryType, MessageEvent, WebSocket};
+
+pub(crate) struct Socket {
+    socket: WebSocket,
+    /// Filled by [Self::_on_message] as messages arrive
+    received: Rc<RefCell<Vec<Vec<u8>>>>,
+    /// Payloads sent before the socket finished opening
+    pending: Vec<Vec<u8>>,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+}
+impl Socket {
+    /// Starts opening a WebSocket to the server, payloads sent before it is open are queued
+    pub(crate) fn open(addr: &str) -> io::Result<Socket> {
+        let socket = WebSocket::new(addr)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to open WebSocket"))?;
+        socket.set_binary_type(BinaryType::Arraybuffer);
+
+        let received = Rc::new(RefCell::new(vec![]));
+        let on_message = {
+            let received = received.clone();
+            Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+                if let Ok(buf) = event.data().dyn_into::<ArrayBuffer>() {
+                    received.borrow_mut().push(Uint8Array::new(&buf).to_vec());
+                }
+            })
+        };
+        socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+
+        Ok(Socket {
+            socket,
+            received,
+            pending: vec![],
+            _on_message: on_message,
+        })
+    }
+
+    fn flush(&mut self) {
+        if self.socket.ready_state() != WebSocket::OPEN {
+            return;
+        }
+        for payload in self.pending.drain(..) {
+            let _ = self.socket.send_with_u8_array(&payload);
+        }
+    }
+
+    pub(crate) fn send(&mut self, payload: Vec<u8>) {
+        self.pending.push(payload);
+        self.flush();
+    }
+
+    /// Returns every payload received since the last call
+    pub(crate) fn receive(&mut self) -> Vec<Vec<u8>> {
+        self.flush();
+        self.received.borrow_mut().drain(..).collect()
+    }
+}
+impl Drop for Socket {
+    fn drop(&mut self) {
+        self.socket.set_onmessage(None);
+        let _ = self.socket.close();
+    }
+}
@@ -1,4 +1,4 @@
-use ak_server::types_game::Texture;
+use ak_server::types_game::{BuildingKind, ServerBuilding, Texture};
 use enum_assoc::Assoc;
 use enum_dispatch::enum_dispatch;
 use macroquad::prelude::UVec2;
@@ -37,6 +37,16 @@ pub(crate) enum Building {
     House,
 }
 impl Building {
+    pub(crate) fn as_server(&self) -> ServerBuilding {
+        let kind = match self {
+            Building::House(_) => BuildingKind::House,
+        };
+        ServerBuilding {
+            kind,
+            pos: self.pos().into(),
+        }
+    }
+
     /// Size of the ore building in tiles
     pub(crate) fn size(&self) -> (u32, u32) {
         let texture = self.texture().texture();
@@ -1,9 +1,8 @@
-use ak_server::types_game::Texture;
+use ak_server::types_game::{OreKind, Texture};
 use derive_new::new;
 use enum_assoc::Assoc;
 use macroquad::prelude::{Color, UVec2, GOLD, RED, WHITE};
 use macroquad::shapes::draw_rectangle;
-use macroquad::time::get_time;
 use rustc_hash::FxHashMap;
 use strum_macros::EnumIter;
 
@@ -28,6 +27,12 @@ pub(crate) enum Ore {
     Gold,
 }
 impl Ore {
+    pub(crate) fn as_server(&self) -> OreKind {
+        match self {
+            Ore::Gold => OreKind::Gold,
+        }
+    }
+
     /// Size of the ore patch in tiles
     pub(crate) fn size(&self) -> (u32, u32) {
         let texture = self.texture().texture();
@@ -85,25 +90,26 @@ impl OrePatch {
         draw_rectangle(rect.left(), rect.top() - 10.0, w * radio, 10.0, RED);
     }
 
-    pub(crate) fn mine(&mut self, id: IdType) -> u32 {
+    /// Mines a piece if the worker's cooldown is over, `now` is [crate::game::Game::time] so it doesn't depend on the frame rate
+    pub(crate) fn mine(&mut self, id: IdType, now: f64) -> u32 {
         if let Some(last_mined) = self.mine_cooldowns.get(&id) {
-            if get_time() - last_mined < self.ore.cooldown() {
+            if now - last_mined < self.ore.cooldown() {
                 return 0;
             }
         }
 
         if self.remaining > 0 {
             self.remaining -= 1;
-            self.mine_cooldowns.insert(id, get_time());
+            self.mine_cooldowns.insert(id, now);
             1
         } else {
             0
         }
     }
 
-    pub(crate) fn time_left(&self, id: IdType) -> f32 {
+    pub(crate) fn time_left(&self, id: IdType, now: f64) -> f32 {
         (if let Some(last_mined) = self.mine_cooldowns.get(&id) {
-            self.ore.cooldown() - (get_time() - last_mined)
+            self.ore.cooldown() - (now - last_mined)
         } else {
             0.0
         }) as f32
@@ -73,11 +73,30 @@ impl Player {
                 .map(|worker| worker.as_server())
                 .collect(),
             color: self.color,
+            ores: self
+                .ores
+                .iter()
+                .map(|(ore, amount)| (ore.as_server(), *amount))
+                .collect(),
+            buildings: self
+                .buildings
+                .iter()
+                .map(|building| building.as_server())
+                .collect(),
+            ai: false,
+            team: 0,
+            gathered: 0,
         }
     }
 
     /// Updates controlling workers
     pub(crate) fn update_workers(&mut self) {
+        // In a game the player's workers are the server's, the local ones are only for playing offline
+        if game().net.in_game() {
+            game().net.update_workers();
+            return;
+        }
+
         // Selecting workers
         if is_mouse_button_pressed(MouseButton::Left) {
             for (i, worker) in self.workers.iter().enumerate() {
@@ -250,8 +269,10 @@ impl Player {
     }
 
     pub(crate) fn draw(&mut self) {
-        for (i, worker) in self.workers.iter().enumerate() {
-            worker.draw(self.selected_worker == Some(i));
+        if !game().net.in_game() {
+            for (i, worker) in self.workers.iter().enumerate() {
+                worker.draw(self.selected_worker == Some(i));
+            }
         }
         self.draw_ui();
     }
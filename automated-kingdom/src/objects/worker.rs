@@ -6,7 +6,6 @@ use macroquad::color_u8;
 use macroquad::prelude::{uvec2, vec2, Color, UVec2, Vec2, RED, WHITE};
 use macroquad::shapes::{draw_line, draw_rectangle};
 use macroquad::texture::DrawTextureParams;
-use macroquad::time::get_frame_time;
 use rustc_hash::FxHashMap;
 
 use crate::astar::{astar, path_time};
@@ -144,9 +143,11 @@ derive_id_eq!(Worker);
 impl Worker {
     pub(crate) fn as_server(&self) -> ServerWorker {
         let sprite = self.spritesheet()[&self.direction].as_server();
+        let pos = self.rect.top_left().into();
         ServerWorker {
-            pos: self.rect.top_left().into(),
             sprite,
+            mining: self.mining,
+            ..ServerWorker::new(self.id as u32, self.color, pos)
         }
     }
 
@@ -207,15 +208,15 @@ impl Worker {
         self.spritesheet_mut().get_mut(&dir).unwrap()
     }
 
-    /// Moves the worker based on the current `path`. Changes [Self::hspd] and [Self::vspd]
-    fn update_path(&mut self) {
+    /// Moves the worker based on the current `path` for `dt` seconds. Changes [Self::hspd] and [Self::vspd]
+    fn update_path(&mut self, dt: f32) {
         if let Some(path) = &mut self.path {
             if !path.is_empty() {
                 let next_pos = path[0];
 
                 let dist = distance(&self.rect.top_left(), &next_pos);
                 let angle = angle(&self.rect.top_left(), &next_pos);
-                let speed = self.speed * get_frame_time();
+                let speed = self.speed * dt;
 
                 let new_pos;
                 if dist > speed {
@@ -235,7 +236,7 @@ impl Worker {
     }
 
     /// Make sure the worker doesn't collide with other workers or walls, if it does, slowly move it out of the way. Changes [Self::hspd] and [Self::vspd]
-    pub(crate) fn update_collision(&mut self) {
+    pub(crate) fn update_collision(&mut self, dt: f32) {
         for worker in
             workers_iter().filter(|w| w.id != self.id && w.moving_away_from != Some(self.id))
         {
@@ -246,7 +247,7 @@ impl Worker {
                 self.moving_away_from = Some(worker.id);
 
                 let angle = opposite_angle(&rect.top_left(), &worker.rect.top_left());
-                let speed = 50.0 * get_frame_time();
+                let speed = 50.0 * dt;
                 let new_pos = project(&rect.top_left(), angle, speed);
 
                 self.hspd += new_pos.x - rect.top_left().x;
@@ -308,7 +309,7 @@ impl Worker {
             if ore.as_rect().touches_rect(&expanded_rect) {
                 self.path = None;
                 self.mining = true;
-                let amt = ore.mine(self.id);
+                let amt = ore.mine(self.id, game().time);
                 if amt > 0 {
                     let ore_map_ref = &mut game().player_mut(self.color).ores;
                     let ore_map = ore_map_ref.entry(ore.ore).or_insert(0);
@@ -346,16 +347,17 @@ impl Worker {
         }
     }
 
-    pub(crate) fn update(&mut self) {
+    /// Advances the worker by `dt` seconds, always called with [crate::game::STEP] so workers move the same at any frame rate
+    pub(crate) fn update(&mut self, dt: f32) {
         // Reset velocities
         self.hspd = 0.0;
         self.vspd = 0.0;
 
         // Movement
         self.update_ore();
-        self.update_path();
+        self.update_path(dt);
         self.update_direction();
-        self.update_collision();
+        self.update_collision(dt);
 
         // Updating spritesheet
         self.sprite_mut().update();
@@ -389,7 +391,7 @@ impl Worker {
             );
 
             let ore = &game().map.ores[self.ore.unwrap()];
-            let ratio = ore.time_left(self.id) / ore.ore.cooldown() as f32;
+            let ratio = ore.time_left(self.id, game().time) / ore.ore.cooldown() as f32;
             let width = self.rect.width;
             let height = 2.5;
             draw_rectangle(